@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use bifrons::data::assetio::VirtualFile;
+use bifrons::data::assetio::is_zip_mod;
+use bifrons::data::module::ModEntry;
+use bifrons::data::module::ModList;
+use bifrons::data::module::Module;
+use bifrons::system::module::PROFILE_ARG;
+use bifrons::system::module::active_profile;
+use bifrons::system::module::default_modlist;
+use bifrons::system::module::modlist_file;
+use bifrons::util::ron_options;
+use ron::ser::PrettyConfig;
+
+const USAGE: &str = "Usage: modlist_manager [--profile <name>] <command>
+
+Without a profile, this.modlist.ron is used. The profile can also be set with BIFRONS_MODLIST_PROFILE.
+
+Commands:
+    list            Lists every mod under mods/, and whether the modlist enables it
+    enable <name>   Enables a mod, adding it to the modlist if it isn't there yet
+    disable <name>  Disables a mod, keeping its entry and settings in the modlist
+    order           Shows the order the enabled mods load in
+    validate        Checks the enabled mods' dependencies, versions and settings";
+
+const CORE_MOD_FILE: &str = "assets/core.mod.ron";
+const MODS_DIR: &str = "mods";
+
+fn main() -> ExitCode {
+    // run from the game's root, like the game itself, so modlist paths resolve the same way
+    let root = match env::var("CARGO_MANIFEST_DIR") {
+        Ok(cargo_dir) => {
+            let mut root = PathBuf::from(cargo_dir);
+            root.pop();
+            root.pop();
+            root
+        },
+        Err(_) => env::current_dir().unwrap(),
+    };
+    env::set_current_dir(&root).unwrap();
+
+    let file = modlist_file(active_profile().as_deref());
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if let Some(i) = args.iter().position(|a| a == PROFILE_ARG) {
+        args.drain(i..(i + 2).min(args.len()));
+    }
+    let result = match args.iter().map(|s| s.as_str()).collect::<Vec<_>>().as_slice() {
+        ["list"]            => list(&file),
+        ["enable", name]    => set_enabled(&file, name, true),
+        ["disable", name]   => set_enabled(&file, name, false),
+        ["order"]           => order(&file),
+        ["validate"]        => validate(&file),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        },
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// Reads the modlist, which starts out with only the core mod if the file doesn't exist yet
+fn read_modlist(file: &str) -> Result<ModList, String> {
+    if !Path::new(file).is_file() {
+        return Ok(default_modlist());
+    }
+    let bytes = fs::read(file)
+        .map_err(|e| format!("Unable to open {}: {}", file, e))?;
+    ron_options().from_bytes(&bytes)
+        .map_err(|e| format!("Unable to deserialize {}: {}", file, e))
+}
+
+fn write_modlist(file: &str, modlist: &ModList) -> Result<(), String> {
+    let s = ron::ser::to_string_pretty(modlist, PrettyConfig::default())
+        .expect("ModList should never fail to serialize");
+    if let Some(dir) = Path::new(file).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.to_string_lossy(), e))?;
+    }
+    fs::write(file, s)
+        .map_err(|e| format!("Unable to write to {}: {}", file, e))
+}
+
+/// Finds core, every `.mod.ron` directly inside a directory under `mods/`, and every zipped mod there
+fn available_mods() -> io::Result<Vec<ModEntry>> {
+    let mut found = vec![ModEntry::new(CORE_MOD_FILE.to_string()).unwrap()];
+    let mut dirs: Vec<PathBuf> = fs::read_dir(MODS_DIR)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    dirs.sort();
+    for path in dirs {
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(&path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<io::Result<_>>()?;
+            files.sort();
+            found.extend(files.into_iter()
+                .filter(|p| p.is_file() && p.to_string_lossy().ends_with(".mod.ron"))
+                .filter_map(|p| ModEntry::new(p.to_string_lossy().replace('\\', "/"))));
+        } else if is_zip_mod(&path.to_string_lossy()) {
+            found.extend(ModEntry::new(path.to_string_lossy().replace('\\', "/")));
+        }
+    }
+    Ok(found)
+}
+
+fn read_module(entry: &ModEntry) -> Result<Module, String> {
+    let file = if entry.is_zip() {
+        VirtualFile::Zip { archive: PathBuf::from(&entry.file), entry: format!("{}.mod.ron", entry.name) }
+    } else {
+        VirtualFile::File(PathBuf::from(&entry.file))
+    };
+    let bytes = file.read().map_err(|e| format!("Unable to open {}: {}", entry.file, e))?;
+    let module: Module = ron_options().from_bytes(&bytes)
+        .map_err(|e| format!("Unable to deserialize {}: {}", entry.file, e))?;
+    module.validate_settings().map_err(|e| format!("Mod `{}`: {}", entry.name, e))?;
+    Ok(module)
+}
+
+/// Reads every enabled mod, collecting the ones that can't be read as errors
+fn read_enabled_modules(modlist: &ModList) -> (HashMap<String, Module>, Vec<String>) {
+    let mut modules = HashMap::new();
+    let mut errors = Vec::new();
+    for entry in modlist.entries.iter().filter(|e| e.enabled) {
+        match read_module(entry) {
+            Ok(module) => { modules.insert(entry.name.clone(), module); },
+            Err(e)     => errors.push(e),
+        }
+    }
+    (modules, errors)
+}
+
+fn list(file: &str) -> Result<(), String> {
+    let modlist = read_modlist(file)?;
+    let available = available_mods().map_err(|e| format!("Unable to read {}/: {}", MODS_DIR, e))?;
+    for entry in available.iter() {
+        let status = match modlist.entries.iter().find(|e| e.name == entry.name) {
+            Some(e) if e.enabled => "enabled",
+            Some(_)              => "disabled",
+            None                 => "not in modlist",
+        };
+        match read_module(entry) {
+            Ok(module) => {
+                let title = module.lines.get("english").and_then(|l| l.get("name")).cloned().unwrap_or_default();
+                println!("{} {} [{}] {} ({})", entry.name, module.version, status, title, entry.file);
+            },
+            Err(e) => println!("{} [{}] ({}): {}", entry.name, status, entry.file, e),
+        }
+    }
+    for entry in modlist.entries.iter().filter(|e| !available.iter().any(|a| a.name == e.name)) {
+        println!("{} [missing] ({})", entry.name, entry.file);
+    }
+    Ok(())
+}
+
+fn set_enabled(file: &str, name: &str, enabled: bool) -> Result<(), String> {
+    let mut modlist = read_modlist(file)?;
+    match modlist.entries.iter_mut().find(|e| e.name == name) {
+        Some(entry) => entry.enabled = enabled,
+        None if enabled => {
+            let available = available_mods().map_err(|e| format!("Unable to read {}/: {}", MODS_DIR, e))?;
+            let entry = available.into_iter()
+                .find(|e| e.name == name)
+                .ok_or_else(|| format!("There is no mod named `{}` under {}/", name, MODS_DIR))?;
+            read_module(&entry)?;
+            modlist.entries.push(entry);
+        },
+        None => return Err(format!("Mod `{}` isn't in {}", name, file)),
+    }
+    write_modlist(file, &modlist)?;
+    println!("{} `{}`", if enabled { "Enabled" } else { "Disabled" }, name);
+    Ok(())
+}
+
+fn order(file: &str) -> Result<(), String> {
+    let modlist = read_modlist(file)?;
+    let (modules, errors) = read_enabled_modules(&modlist);
+    if let Some(e) = errors.first() {
+        return Err(e.clone());
+    }
+    let mods = modules.iter().map(|(k, v)| (k.clone(), v)).collect();
+    let waves = Module::sorted_load_order(&mods).map_err(|e| e.to_string())?;
+    for (i, wave) in waves.iter().enumerate() {
+        println!("{}: {}", i + 1, wave.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", "));
+    }
+    Ok(())
+}
+
+fn validate(file: &str) -> Result<(), String> {
+    let modlist = read_modlist(file)?;
+    let (modules, mut errors) = read_enabled_modules(&modlist);
+    let mods = modules.iter().map(|(k, v)| (k.clone(), v)).collect();
+    let (ordered, mod_errors) = Module::recovered_load_order(&mods);
+    errors.extend(mod_errors.iter().map(|e| e.to_string()));
+    for name in ordered.iter().flatten() {
+        let values = modlist.entries.iter()
+            .find(|e| &e.name == name)
+            .map(|e| e.settings.clone())
+            .unwrap_or_default();
+        let (_, setting_errors) = modules[name].resolve_settings(&values);
+        errors.extend(setting_errors.into_iter().map(|e| format!("Mod `{}`: {}", name, e)));
+    }
+
+    if errors.is_empty() {
+        println!("{} enabled mod(s) are valid", modules.len());
+        Ok(())
+    } else {
+        for e in errors.iter() {
+            println!("{}", e);
+        }
+        Err(format!("{} problem(s) found in {}", errors.len(), file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::panic;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use bifrons::system::module::modlist_file;
+
+    use super::{order, read_modlist, set_enabled, validate};
+
+    /// Every command resolves paths from the working directory, which all tests share
+    static WORKING_DIR: Mutex<()> = Mutex::new(());
+
+    fn write_mod(root: &Path, file: &str, ron: &str) {
+        let path = root.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, ron).unwrap();
+    }
+
+    /// Runs `f` from a game root with core and a `dark_mode` mod that depends on `palette_lib`, which isn't there
+    fn in_game_root(name: &str, f: impl FnOnce(&Path) + panic::UnwindSafe) {
+        let _lock = WORKING_DIR.lock().unwrap_or_else(|e| e.into_inner());
+        let root = env::temp_dir().join(format!("bifrons_modlist_manager_{}_{}", name, std::process::id()));
+        write_mod(&root, "assets/core.mod.ron", "(version: (0,1,0), priority: -999999999.)");
+        write_mod(&root, "mods/dark_mode/dark_mode.mod.ron", r#"(version: (1,0,0), priority: 0., dependencies: { "core": Any, "palette_lib": Any })"#);
+
+        let previous = env::current_dir().unwrap();
+        env::set_current_dir(&root).unwrap();
+        let result = panic::catch_unwind(|| f(&root));
+        env::set_current_dir(previous).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        if let Err(e) = result {
+            panic::resume_unwind(e);
+        }
+    }
+
+    #[test]
+    fn enable_disable_round_trip() {
+        in_game_root("toggle", |_| {
+            let file = modlist_file(Some("test"));
+            let enabled = |name: &str| read_modlist(&file).unwrap().entries.iter().find(|e| e.name == name).map(|e| e.enabled);
+
+            set_enabled(&file, "dark_mode", true).unwrap();
+            assert_eq!(Some(true), enabled("core"));
+            assert_eq!(Some(true), enabled("dark_mode"));
+            assert_eq!("mods/dark_mode/dark_mode.mod.ron", read_modlist(&file).unwrap().entries[1].file);
+
+            // disabling keeps the entry, and enabling it again doesn't add another
+            set_enabled(&file, "dark_mode", false).unwrap();
+            assert_eq!(Some(false), enabled("dark_mode"));
+            set_enabled(&file, "dark_mode", true).unwrap();
+            assert_eq!(Some(true), enabled("dark_mode"));
+            assert_eq!(2, read_modlist(&file).unwrap().entries.len());
+
+            assert!(set_enabled(&file, "missing", true).is_err());
+            assert!(set_enabled(&file, "missing", false).is_err());
+            assert!(!Path::new("this.modlist.ron").exists(), "a profile shouldn't touch the default modlist");
+        });
+    }
+
+    #[test]
+    fn validate_modlist() {
+        in_game_root("validate", |root| {
+            let file = modlist_file(None);
+            validate(&file).unwrap();
+
+            set_enabled(&file, "dark_mode", true).unwrap();
+            assert!(validate(&file).is_err(), "dark_mode needs palette_lib, which isn't enabled");
+            assert!(order(&file).is_err());
+
+            write_mod(root, "mods/palette_lib/palette_lib.mod.ron", "(version: (0,3,0), priority: -10.)");
+            set_enabled(&file, "palette_lib", true).unwrap();
+            validate(&file).unwrap();
+            order(&file).unwrap();
+
+            // disabling a dependency breaks the mods that need it again
+            set_enabled(&file, "palette_lib", false).unwrap();
+            assert!(validate(&file).is_err());
+        });
+    }
+}
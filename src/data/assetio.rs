@@ -1,64 +1,309 @@
-use std::{collections::HashMap, path::{PathBuf, Path}, io::{Read, self}, fs, sync::Arc};
+use std::{collections::{HashMap, HashSet}, default::default, path::{PathBuf, Path}, io::{Read, self}, fs, sync::Arc, time::SystemTime};
 
-use bevy::{asset::{FileAssetIo, AssetIo, AssetIoError}, prelude::{Resource}};
-use parking_lot::{RwLock, RwLockWriteGuard};
+use bevy::{asset::{FileAssetIo, AssetIo, AssetIoError, Metadata, FileType}, prelude::{Resource, warn}};
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
 use std::fs::File;
+use zip::ZipArchive;
 
-pub type OverridesLock = Arc<RwLock<HashMap<PathBuf, PathBuf>>>;
+use super::{module::LoadEntry, patch::{patch_target, patch_ron}};
+
+pub type OverridesLock = Arc<RwLock<HashMap<PathBuf, VirtualFile>>>;
+/// Every directory with zipped files somewhere under it, with the zipped files and directories directly inside it
+pub type ZippedDirsLock = Arc<RwLock<HashMap<PathBuf, HashSet<PathBuf>>>>;
+
+lazy_static! {
+    /// Archives stay open once read from, so reading each zipped file doesn't parse the archive's central directory again
+    static ref OPEN_ARCHIVES: Mutex<HashMap<PathBuf, OpenArchive>> = Mutex::new(HashMap::new());
+}
+
+struct OpenArchive {
+    /// When the archive was last modified as of opening it, so it's opened again once it changes
+    modified: SystemTime,
+    zip:      Arc<Mutex<ZipArchive<File>>>,
+}
+
+/// The archive at this path, opening it unless it's already open and unchanged on disk
+fn open_archive(path: &Path) -> io::Result<Arc<Mutex<ZipArchive<File>>>> {
+    let modified = fs::metadata(path)?.modified()?;
+    let mut open = OPEN_ARCHIVES.lock();
+    if let Some(archive) = open.get(path) && archive.modified == modified {
+        return Ok(archive.zip.clone());
+    }
+    let zip = Arc::new(Mutex::new(ZipArchive::new(File::open(path)?).map_err(|e| io::Error::other(e))?));
+    open.insert(path.to_path_buf(), OpenArchive { modified, zip: zip.clone() });
+    Ok(zip)
+}
+
+/// Closes every open archive, like when the mods providing them might have changed
+pub fn close_archives() {
+    OPEN_ARCHIVES.lock().clear();
+}
+
+/// Where the real contents of an overridden asset path live
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VirtualFile {
+    File(PathBuf),
+    Zip {
+        archive: PathBuf,
+        entry:   String,
+    },
+    /// A RON asset with `.ron.patch` files from later mods applied to it, in load order
+    Patched {
+        base:    Box<VirtualFile>,
+        patches: Vec<VirtualFile>,
+    },
+}
+impl VirtualFile {
+    /// The file on disk backing this, which for zipped files is the archive itself
+    pub fn source_path(&self) -> &Path {
+        match self {
+            VirtualFile::File(path)          => path,
+            VirtualFile::Zip { archive, .. } => archive,
+            VirtualFile::Patched { base, .. } => base.source_path(),
+        }
+    }
+
+    /// Every file on disk this is read from, which for patched files includes each patch
+    pub fn source_paths(&self) -> Vec<&Path> {
+        match self {
+            VirtualFile::Patched { base, patches } => base.source_paths().into_iter()
+                .chain(patches.iter().flat_map(|p| p.source_paths()))
+                .collect(),
+            _ => vec![self.source_path()],
+        }
+    }
+
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        match self {
+            VirtualFile::File(path) => {
+                File::open(path)?.read_to_end(&mut bytes)?;
+            },
+            VirtualFile::Zip { archive, entry } => {
+                let zip = open_archive(archive)?;
+                let mut zip = zip.lock();
+                let mut file = zip.by_name(entry)
+                    .map_err(|e| match e {
+                        zip::result::ZipError::FileNotFound => io::Error::new(io::ErrorKind::NotFound, e),
+                        e => io::Error::other(e),
+                    })?;
+                file.read_to_end(&mut bytes)?;
+            },
+            VirtualFile::Patched { base, patches } => {
+                let patches = patches.iter()
+                    .map(|p| p.read())
+                    .collect::<io::Result<Vec<Vec<u8>>>>()?;
+                bytes = patch_ron(&base.read()?, &patches)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            },
+        }
+        Ok(bytes)
+    }
+
+    /// When this was last modified on disk, which for patched files is the latest of the base and its patches
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        match self {
+            VirtualFile::Patched { base, patches } => patches.iter()
+                .map(|p| p.modified())
+                .try_fold(base.modified()?, |latest, t| Ok(latest.max(t?))),
+            _ => fs::metadata(self.source_path())?.modified(),
+        }
+    }
+}
+
+pub fn is_zip_mod(mod_path: &str) -> bool {
+    mod_path.to_ascii_lowercase().ends_with(".zip")
+}
+
+/// Asset paths starting with `super/<mod name>/` load the version of a file that the named mod overrode,
+/// so patches can wrap the original instead of copying it
+pub const SUPER_DIR: &str = "super";
+
+pub fn super_path(mod_name: &str, path: &Path) -> PathBuf {
+    Path::new(SUPER_DIR).join(mod_name).join(path)
+}
 
 #[derive(Resource)]
 pub struct VirtualFileOverrides {
-    pub overrides: OverridesLock,
-    pub lua_path:  String,
+    pub overrides:   OverridesLock,
+    pub zipped_dirs: ZippedDirsLock,
+    pub lua_path:    String,
+    /// Every mod that provides each overridden asset path, from the first loaded to the one that won
+    pub providers:   HashMap<PathBuf, Vec<LoadEntry>>,
 }
 impl VirtualFileOverrides {
-    pub fn populate_files(&mut self, load_order: &Vec<Vec<&String>>) {
-        fn visit_dirs(dir: &Path, root: &Path, overrides: &mut RwLockWriteGuard<HashMap<PathBuf, PathBuf>>) -> io::Result<()> {
+    pub fn populate_files(&mut self, load_order: &Vec<Vec<LoadEntry>>) -> Vec<(LoadEntry, io::Error)> {
+        self.populate_files_in(&FileAssetIo::get_base_path(), load_order)
+    }
+
+    /// Maps every file in each mod's directory (or zip archive) under `base_path` to its asset path, with later mods in the load order overriding earlier ones
+    /// 
+    /// Each overridden version stays reachable through [`super_path`] of the mod that overrode it.
+    /// Mods whose directory or archive can't be read provide nothing, and are returned along with why
+    pub fn populate_files_in(&mut self, base_path: &Path, load_order: &Vec<Vec<LoadEntry>>) -> Vec<(LoadEntry, io::Error)> {
+        fn visit_dirs(dir: &Path, root: &Path, found: &mut Vec<(PathBuf, VirtualFile)>) -> io::Result<()> {
             if dir.is_dir() {
                 for entry in fs::read_dir(dir)? {
                     let entry = entry?;
                     let path = entry.path();
                     if path.is_dir() {
-                        visit_dirs(&path, root, overrides)?;
+                        visit_dirs(&path, root, found)?;
                     } else if let Some(filename) = path.file_name()
                            && !filename.to_string_lossy().ends_with(".mod.ron") {
                         let stripped = path.as_path().strip_prefix(root)
                             .map_err(|e| io::Error::other(e))?;
-                        overrides.insert(PathBuf::from(stripped), path);
+                        found.push((PathBuf::from(stripped), VirtualFile::File(path)));
                     }
                 }
             }
             Ok(())
         }
+        fn visit_zip(archive: &Path, found: &mut Vec<(PathBuf, VirtualFile)>) -> io::Result<()> {
+            let zip = open_archive(archive)?;
+            let zip = zip.lock();
+            for entry in zip.file_names() {
+                if !entry.ends_with('/') && !entry.ends_with(".mod.ron") {
+                    found.push((PathBuf::from(entry), VirtualFile::Zip { archive: archive.to_path_buf(), entry: entry.to_string() }));
+                }
+            }
+            Ok(())
+        }
+        // archives are visited again from scratch, and ones that no longer belong to a loaded mod are closed
+        close_archives();
         let mut lua_paths = Vec::<String>::new();
-        let mut overrides = self.overrides.write();
-        overrides.clear();
+        let mut layers: HashMap<PathBuf, Vec<(LoadEntry, VirtualFile, bool)>> = HashMap::new();
+        let mut unreadable = Vec::new();
+        let mut core = None;
         for wave in load_order.iter() {
-            for mod_path in wave.iter() {
-                if mod_path.as_str() == "assets" {
-                    lua_paths.push(format!("{}/assets/?.lua", FileAssetIo::get_base_path().to_string_lossy()));
+            for entry in wave.iter() {
+                let mut found = Vec::new();
+                if entry.root.as_str() == "assets" {
+                    // core files are served straight from ./assets, and only need tracking once a mod overrides them
+                    lua_paths.push(format!("{}/assets/?.lua", base_path.to_string_lossy()));
+                    core = Some(entry);
+                    continue;
+                } else if is_zip_mod(&entry.root) {
+                    // zipped scripts can't be found through package.path, so require falls back to a searcher over these overrides
+                    if let Err(e) = visit_zip(&base_path.join(entry.root.as_str()), &mut found) {
+                        unreadable.push((entry.clone(), e));
+                        continue;
+                    }
                 } else {
-                    let path_str = format!("{}/{}", FileAssetIo::get_base_path().to_string_lossy(), mod_path);
+                    let path_str = format!("{}/{}", base_path.to_string_lossy(), entry.root);
                     let path     = Path::new(&path_str);
-                    visit_dirs(&path, &path, &mut overrides).unwrap();
+                    if let Err(e) = visit_dirs(&path, &path, &mut found) {
+                        unreadable.push((entry.clone(), e));
+                        continue;
+                    }
                     lua_paths.push(format!("{}/?.lua", path_str));
                 }
+                for (path, file) in found {
+                    let (path, is_patch) = match patch_target(&path.to_string_lossy()) {
+                        Some(target) => (PathBuf::from(target), true),
+                        None         => (path, false),
+                    };
+                    let stack = layers.entry(path.clone()).or_insert_with(|| {
+                        let core_file = base_path.join("assets").join(&path);
+                        match core {
+                            Some(core) if core_file.is_file() => vec![(core.clone(), VirtualFile::File(core_file), false)],
+                            _ => Vec::new(),
+                        }
+                    });
+                    stack.push((entry.clone(), file, is_patch));
+                }
+            }
+        }
+
+        let mut overrides = self.overrides.write();
+        overrides.clear();
+        self.providers.clear();
+        for (path, layer_stack) in layers {
+            // each mod's version of the file is whatever it replaced it with, or the version below with its patch applied
+            let mut stack: Vec<(LoadEntry, VirtualFile)> = Vec::new();
+            for (entry, file, is_patch) in layer_stack {
+                let file = if is_patch {
+                    match stack.last() {
+                        Some((_, VirtualFile::Patched { base, patches })) => VirtualFile::Patched {
+                            base:    base.clone(),
+                            patches: patches.iter().cloned().chain(Some(file)).collect(),
+                        },
+                        Some((_, below)) => VirtualFile::Patched { base: Box::new(below.clone()), patches: vec![file] },
+                        None => {
+                            warn!("Mod `{}` patches {}, but no mod before it provides that file", entry.name, path.to_string_lossy());
+                            continue;
+                        },
+                    }
+                } else { file };
+                stack.push((entry, file));
+            }
+            for pair in stack.windows(2) {
+                let (below, file) = &pair[0];
+                let super_path = super_path(&pair[1].0.name, &path);
+                overrides.insert(super_path.clone(), file.clone());
+                self.providers.insert(super_path, vec![below.clone()]);
+            }
+            let mut stack = stack.into_iter();
+            if let Some((entry, file)) = stack.next_back() {
+                overrides.insert(path.clone(), file);
+                let mut providers: Vec<LoadEntry> = stack.map(|(e, _)| e).collect();
+                providers.push(entry);
+                self.providers.insert(path, providers);
+            }
+        }
+        let mut zipped_dirs = self.zipped_dirs.write();
+        zipped_dirs.clear();
+        for path in overrides.iter().filter(|(_, v)| matches!(v, VirtualFile::Zip { .. })).map(|(k, _)| k) {
+            let mut child = path.as_path();
+            while let Some(dir) = child.parent() {
+                zipped_dirs.entry(dir.to_path_buf()).or_default().insert(child.to_path_buf());
+                child = dir;
             }
         }
         lua_paths.reverse();
         self.lua_path = lua_paths.join(";");
+        unreadable
+    }
+
+    /// The mod whose version of this asset path is used, if it isn't core's own file
+    pub fn owner(&self, path: &Path) -> Option<&LoadEntry> {
+        self.providers.get(path).and_then(|p| p.last())
+    }
+
+    /// Every asset path provided by more than one mod, sorted by path
+    pub fn conflicts(&self) -> Vec<(&PathBuf, &Vec<LoadEntry>)> {
+        let mut conflicts: Vec<(&PathBuf, &Vec<LoadEntry>)> = self.providers.iter()
+            .filter(|(_, providers)| providers.len() > 1)
+            .collect();
+        conflicts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        conflicts
+    }
+
+    /// Lists which mods provide each conflicting asset path, and which one won
+    pub fn conflict_report(&self) -> String {
+        self.conflicts().into_iter()
+            .map(|(path, providers)| format!(
+                "{}: {} (using {})",
+                path.to_string_lossy(),
+                providers.iter().map(|e| e.name.as_str()).intersperse(" < ").collect::<String>(),
+                providers.last().map(|e| e.name.as_str()).unwrap_or_default(),
+            ))
+            .intersperse("\n".to_string())
+            .collect()
     }
 }
 
 pub struct VirtualAssetIo {
     pub file_io: FileAssetIo,
     overrides:   OverridesLock,
+    zipped_dirs: ZippedDirsLock,
 }
 impl VirtualAssetIo {
     pub fn new() -> VirtualAssetIo {
         VirtualAssetIo {
-            file_io:   FileAssetIo::new("assets", false),
-            overrides: Arc::new(RwLock::new(HashMap::new())),
+            file_io:     FileAssetIo::new("assets", false),
+            overrides:   default(),
+            zipped_dirs: default(),
         }
     }
 
@@ -66,47 +311,66 @@ impl VirtualAssetIo {
         self.overrides.clone()
     }
 
+    pub fn zipped_dirs(&self) -> ZippedDirsLock {
+        self.zipped_dirs.clone()
+    }
+
     pub fn root_path(&self) -> &PathBuf {
         self.file_io.root_path()
     }
+
+    /// The file an asset path loads from, whether it's overridden or not
+    pub fn resolve(&self, path: &Path) -> VirtualFile {
+        self.overrides.read().get(path).cloned().unwrap_or_else(|| VirtualFile::File(self.root_path().join(path)))
+    }
+
+    /// True if any zipped file lives under this path, since archives have no directories on disk to check
+    fn is_zipped_dir(&self, path: &Path) -> bool {
+        self.zipped_dirs.read().contains_key(path)
+    }
 }
 impl AssetIo for VirtualAssetIo {
     fn get_metadata(&self, path: &std::path::Path) -> Result<bevy::asset::Metadata, bevy::asset::AssetIoError> {
         let read = self.overrides.read();
-        let path: &Path = read.get(path).map(|p| &p as &Path).unwrap_or(&path);
-        self.file_io.get_metadata(&path)
+        match read.get(path) {
+            Some(VirtualFile::File(p))    => self.file_io.get_metadata(&p),
+            Some(VirtualFile::Zip { .. } | VirtualFile::Patched { .. }) => Ok(Metadata::new(FileType::File)),
+            None if self.is_zipped_dir(path) => Ok(Metadata::new(FileType::Directory)),
+            None => self.file_io.get_metadata(&path),
+        }
     }
 
     fn is_dir(&self, path: &std::path::Path) -> bool {
         let read = self.overrides.read();
-        let path: &Path = read.get(path).map(|p| &p as &Path).unwrap_or(&path);
-        self.file_io.is_dir(&path)
+        match read.get(path) {
+            Some(VirtualFile::File(p))    => self.file_io.is_dir(&p),
+            Some(VirtualFile::Zip { .. } | VirtualFile::Patched { .. }) => false,
+            None => self.is_zipped_dir(path) || self.file_io.is_dir(&path),
+        }
     }
 
     fn is_file(&self, path: &std::path::Path) -> bool {
         let read = self.overrides.read();
-        let path: &Path = read.get(path).map(|p| &p as &Path).unwrap_or(&path);
-        self.file_io.is_file(&path)
+        match read.get(path) {
+            Some(VirtualFile::File(p))    => self.file_io.is_file(&p),
+            Some(VirtualFile::Zip { .. } | VirtualFile::Patched { .. }) => true,
+            None => self.file_io.is_file(&path),
+        }
     }
 
     fn load_path<'a>(&'a self, path: &'a std::path::Path) -> bevy::utils::BoxedFuture<'a, Result<Vec<u8>, bevy::asset::AssetIoError>> {
-        let read = self.overrides.read();
-        let full_path: PathBuf = read.get(path).cloned().unwrap_or_else(|| self.root_path().join(path));
+        let file = self.resolve(path);
         Box::pin(async move {
-            let mut bytes = Vec::new();
-            match File::open(&full_path) {
-                Ok(mut file) => {
-                    file.read_to_end(&mut bytes)?;
-                }
+            match file.read() {
+                Ok(bytes) => Ok(bytes),
                 Err(e) => {
-                    return if e.kind() == std::io::ErrorKind::NotFound {
-                        Err(AssetIoError::NotFound(full_path))
+                    if e.kind() == std::io::ErrorKind::NotFound {
+                        Err(AssetIoError::NotFound(file.source_path().to_path_buf()))
                     } else {
                         Err(e.into())
                     }
                 }
             }
-            Ok(bytes)
         })
     }
 
@@ -115,8 +379,20 @@ impl AssetIo for VirtualAssetIo {
             path: &std::path::Path,
         ) -> Result<Box<dyn Iterator<Item = PathBuf>>, bevy::asset::AssetIoError> {
         let read = self.overrides.read();
-        let path: &Path = read.get(path).map(|p| &p as &Path).unwrap_or(&path);
-        self.file_io.read_directory(&path)
+        let zipped = self.zipped_dirs.read().get(path).cloned().unwrap_or_default();
+        let path: &Path = match read.get(path) {
+            Some(VirtualFile::File(p)) => p,
+            _ => path,
+        };
+        match self.file_io.read_directory(&path) {
+            Ok(iter) => {
+                // a directory can be both on disk and in an archive, but should only be listed once
+                let on_disk: Vec<PathBuf> = iter.filter(|p| !zipped.contains(p)).collect();
+                Ok(Box::new(on_disk.into_iter().chain(zipped.into_iter())))
+            },
+            Err(_) if !zipped.is_empty() => Ok(Box::new(zipped.into_iter())),
+            Err(e) => Err(e),
+        }
     }
 
     fn watch_for_changes(&self) -> Result<(), bevy::asset::AssetIoError> {
@@ -125,7 +401,115 @@ impl AssetIo for VirtualAssetIo {
 
     fn watch_path_for_changes(&self, path: &std::path::Path) -> Result<(), bevy::asset::AssetIoError> {
         let read = self.overrides.read();
-        let path: &Path = read.get(path).map(|p| &p as &Path).unwrap_or(&path);
-        self.file_io.watch_path_for_changes(&path)
+        match read.get(path) {
+            // a patched file changes when any of its layers do
+            Some(file) => file.source_paths().into_iter().try_for_each(|p| self.file_io.watch_path_for_changes(p)),
+            None => self.file_io.watch_path_for_changes(&path),
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs::{self, File}, io::Write, path::{Path, PathBuf}};
+
+    use bevy::asset::{AssetIo, FileAssetIo};
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use crate::data::module::LoadEntry;
+
+    use super::{VirtualAssetIo, VirtualFile, VirtualFileOverrides, super_path};
+
+    #[test]
+    fn zipped_mod() {
+        let root = std::env::temp_dir().join(format!("bifrons_zipped_mod_{}", std::process::id()));
+        fs::create_dir_all(root.join("assets/textures")).unwrap();
+        fs::write(root.join("assets/textures/floor.png"), "core").unwrap();
+        fs::create_dir_all(root.join("mods")).unwrap();
+        let mut zip = ZipWriter::new(File::create(root.join("mods/zipped.zip")).unwrap());
+        for (entry, contents) in [("zipped.mod.ron", "()"), ("scripts/util.lua", "return 1"), ("textures/wall.png", "zipped")] {
+            zip.start_file(entry, FileOptions::default().compression_method(CompressionMethod::Stored)).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let load_order = vec![
+            vec![LoadEntry { name: "core".to_string(), root: "assets".to_string() }],
+            vec![LoadEntry { name: "zipped".to_string(), root: "mods/zipped.zip".to_string() }],
+        ];
+        let mut vfo = VirtualFileOverrides { overrides: Default::default(), zipped_dirs: Default::default(), lua_path: String::new(), providers: Default::default() };
+        vfo.populate_files_in(&root, &load_order);
+        let util = vfo.overrides.read()[Path::new("scripts/util.lua")].clone();
+        assert!(matches!(util, VirtualFile::Zip { .. }));
+        // the second read goes through the archive that's already open
+        assert_eq!(b"return 1".to_vec(), util.read().unwrap());
+        assert_eq!(b"return 1".to_vec(), util.read().unwrap());
+        assert!(!vfo.overrides.read().contains_key(Path::new("zipped.mod.ron")));
+
+        let io = VirtualAssetIo {
+            file_io:     FileAssetIo::new(root.join("assets"), false),
+            overrides:   vfo.overrides.clone(),
+            zipped_dirs: vfo.zipped_dirs.clone(),
+        };
+        assert!(io.is_dir(Path::new("scripts")));
+        assert!(io.is_file(Path::new("scripts/util.lua")));
+        assert!(!io.is_dir(Path::new("scripts/util.lua")));
+        assert!(io.get_metadata(Path::new("scripts")).unwrap().is_dir());
+        let listed = |dir: &str| io.read_directory(Path::new(dir)).unwrap().collect::<HashSet<PathBuf>>();
+        assert_eq!(HashSet::from([PathBuf::from("textures/floor.png"), PathBuf::from("textures/wall.png")]), listed("textures"));
+        assert_eq!(HashSet::from([PathBuf::from("scripts"), PathBuf::from("textures")]), listed(""));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn corrupt_zipped_mod() {
+        let root = std::env::temp_dir().join(format!("bifrons_corrupt_zip_{}", std::process::id()));
+        fs::create_dir_all(root.join("mods/loose")).unwrap();
+        fs::write(root.join("mods/loose/textures.png"), "loose").unwrap();
+        fs::write(root.join("mods/broken.zip"), "PK\x03\x04 this archive was cut short").unwrap();
+
+        let load_order = vec![vec![
+            LoadEntry { name: "broken".to_string(), root: "mods/broken.zip".to_string() },
+            LoadEntry { name: "loose".to_string(), root: "mods/loose".to_string() },
+        ]];
+        let mut vfo = VirtualFileOverrides { overrides: Default::default(), zipped_dirs: Default::default(), lua_path: String::new(), providers: Default::default() };
+        let unreadable = vfo.populate_files_in(&root, &load_order);
+        // the broken archive is reported instead of panicking, and the other mods still provide their files
+        assert_eq!(vec!["broken"], unreadable.iter().map(|(e, _)| e.name.as_str()).collect::<Vec<_>>());
+        assert!(vfo.overrides.read().contains_key(Path::new("textures.png")));
+        assert!(vfo.zipped_dirs.read().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn overridden_providers() {
+        let root = std::env::temp_dir().join(format!("bifrons_providers_{}", std::process::id()));
+        for (mod_name, file) in [("base_textures", "textures/wall.png"), ("base_textures", "textures/floor.png"), ("texture_patch", "textures/wall.png"), ("hd_textures", "textures/wall.png")] {
+            let path = root.join(mod_name).join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, mod_name).unwrap();
+        }
+
+        let load_order: Vec<Vec<LoadEntry>> = vec![vec!["base_textures"], vec!["texture_patch", "hd_textures"]].into_iter()
+            .map(|wave| wave.into_iter().map(|name| LoadEntry { name: name.to_string(), root: name.to_string() }).collect())
+            .collect();
+        let mut vfo = VirtualFileOverrides { overrides: Default::default(), zipped_dirs: Default::default(), lua_path: String::new(), providers: Default::default() };
+        vfo.populate_files_in(&root, &load_order);
+        let overrides = vfo.overrides.read();
+        let owner = |file: &Path| overrides.get(file).map(|p| p.source_path().strip_prefix(&root).unwrap().iter().next().unwrap().to_string_lossy().to_string());
+        assert_eq!(Some("texture_patch".to_string()), owner(&super_path("hd_textures", Path::new("textures/wall.png"))));
+        assert_eq!(Some("base_textures".to_string()), owner(&super_path("texture_patch", Path::new("textures/wall.png"))));
+        assert_eq!(None,                              owner(&super_path("base_textures", Path::new("textures/wall.png"))));
+        drop(overrides);
+
+        let providers = |file: &str| vfo.providers[Path::new(file)].iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["base_textures", "texture_patch", "hd_textures"], providers("textures/wall.png"));
+        assert_eq!(vec!["base_textures"], providers("textures/floor.png"));
+        assert_eq!(1, vfo.conflicts().len());
+        assert_eq!("textures/wall.png: base_textures < texture_patch < hd_textures (using hd_textures)", vfo.conflict_report());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, fs, io, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use super::{assetio::VirtualFile, module::Version};
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is guaranteed to give the same hash on every platform and Rust version
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Everything peers need to agree on before playing together: which mods are loaded in what order,
+/// and the content of every file assets load from, whether it's core's own or provided by a mod
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ModlistFingerprint {
+    /// Each mod's name and version, in load order
+    pub mods:  Vec<(String, Version)>,
+    /// The content hash of every asset path, with `/` separators on every platform
+    pub files: BTreeMap<String, u64>,
+}
+impl ModlistFingerprint {
+    /// Hashes every file in `assets`, then every overridden asset path, which replaces core's version of the file
+    ///
+    /// Zipped files are read through archives that stay open, so each archive is only opened once
+    pub fn new(mods: Vec<(String, Version)>, overrides: &HashMap<PathBuf, VirtualFile>, assets: &Path) -> io::Result<Self> {
+        fn visit_dirs(dir: &Path, root: &Path, files: &mut BTreeMap<String, u64>) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    visit_dirs(&path, root, files)?;
+                } else if let Ok(stripped) = path.strip_prefix(root) {
+                    files.insert(asset_key(stripped), stable_hash(&fs::read(&path)?));
+                }
+            }
+            Ok(())
+        }
+        let mut files = BTreeMap::new();
+        if assets.is_dir() {
+            visit_dirs(assets, assets, &mut files)?;
+        }
+        for (path, file) in overrides.iter() {
+            files.insert(asset_key(path), stable_hash(&file.read()?));
+        }
+        Ok(ModlistFingerprint { mods, files })
+    }
+
+    /// A single number that only matches between peers with identical fingerprints
+    pub fn hash(&self) -> u64 {
+        let mut bytes = Vec::new();
+        for (name, version) in self.mods.iter() {
+            bytes.extend(format!("{}@{}\n", name, version).into_bytes());
+        }
+        for (path, hash) in self.files.iter() {
+            bytes.extend(format!("{}#{:016x}\n", path, hash).into_bytes());
+        }
+        stable_hash(&bytes)
+    }
+
+    /// Explains how `theirs` differs from this fingerprint, which is empty when they match
+    pub fn diff(&self, theirs: &ModlistFingerprint) -> Vec<FingerprintDiff> {
+        let mut diffs = Vec::new();
+        let our_mods: HashMap<&String, &Version> = self.mods.iter().map(|(n, v)| (n, v)).collect();
+        let their_mods: HashMap<&String, &Version> = theirs.mods.iter().map(|(n, v)| (n, v)).collect();
+        for (name, version) in self.mods.iter() {
+            match their_mods.get(name) {
+                None => diffs.push(FingerprintDiff::MissingMod { name: name.clone(), version: *version }),
+                Some(theirs) if *theirs != version => diffs.push(FingerprintDiff::ModVersion { name: name.clone(), ours: *version, theirs: **theirs }),
+                _ => (),
+            }
+        }
+        for (name, version) in theirs.mods.iter() {
+            if !our_mods.contains_key(name) {
+                diffs.push(FingerprintDiff::ExtraMod { name: name.clone(), version: *version });
+            }
+        }
+        if diffs.is_empty() && self.mods != theirs.mods {
+            diffs.push(FingerprintDiff::LoadOrder {
+                ours:   self.mods.iter().map(|(n, _)| n.clone()).collect(),
+                theirs: theirs.mods.iter().map(|(n, _)| n.clone()).collect(),
+            });
+        }
+
+        for (path, hash) in self.files.iter() {
+            match theirs.files.get(path) {
+                None => diffs.push(FingerprintDiff::MissingFile { path: path.clone() }),
+                Some(theirs) if theirs != hash => diffs.push(FingerprintDiff::FileContent { path: path.clone() }),
+                _ => (),
+            }
+        }
+        for path in theirs.files.keys() {
+            if !self.files.contains_key(path) {
+                diffs.push(FingerprintDiff::ExtraFile { path: path.clone() });
+            }
+        }
+        diffs
+    }
+}
+
+fn asset_key(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// One way another peer's modlist differs from ours
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FingerprintDiff {
+    /// We have this mod, but they don't
+    MissingMod {
+        name:    String,
+        version: Version,
+    },
+    /// They have this mod, but we don't
+    ExtraMod {
+        name:    String,
+        version: Version,
+    },
+    ModVersion {
+        name:   String,
+        ours:   Version,
+        theirs: Version,
+    },
+    /// The same mods, loaded in a different order
+    LoadOrder {
+        ours:   Vec<String>,
+        theirs: Vec<String>,
+    },
+    MissingFile {
+        path: String,
+    },
+    ExtraFile {
+        path: String,
+    },
+    FileContent {
+        path: String,
+    },
+}
+impl fmt::Display for FingerprintDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FingerprintDiff::MissingMod { name, version }      => write!(f, "They are missing mod `{}` {}", name, version),
+            FingerprintDiff::ExtraMod { name, version }        => write!(f, "They have mod `{}` {}, which we don't", name, version),
+            FingerprintDiff::ModVersion { name, ours, theirs } => write!(f, "Mod `{}` is {} for us, but {} for them", name, ours, theirs),
+            FingerprintDiff::LoadOrder { ours, theirs }        => write!(f, "Mods load in order {} for us, but {} for them", ours.join(", "), theirs.join(", ")),
+            FingerprintDiff::MissingFile { path }              => write!(f, "They are missing {}", path),
+            FingerprintDiff::ExtraFile { path }                => write!(f, "They have {}, which we don't", path),
+            FingerprintDiff::FileContent { path }              => write!(f, "{} has different contents", path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::{BTreeMap, HashMap}, fs, path::PathBuf};
+
+    use crate::data::assetio::VirtualFile;
+
+    use super::{ModlistFingerprint, FingerprintDiff, stable_hash};
+
+    #[test]
+    fn stable_hash_fnv() {
+        assert_eq!(0xcbf29ce484222325, stable_hash(b""));
+        assert_eq!(0xaf63dc4c8601ec8c, stable_hash(b"a"));
+        assert_eq!(0x85944171f73967e8, stable_hash(b"foobar"));
+    }
+
+    #[test]
+    fn fingerprint_diff() {
+        let ours = ModlistFingerprint {
+            mods:  vec![("core".to_string(), "0.1.0".parse().unwrap()), ("editor".to_string(), "0.1.0".parse().unwrap())],
+            files: BTreeMap::from([("editor/main.lua".to_string(), 1), ("palettes/default.palette.ron".to_string(), 2)]),
+        };
+        assert_eq!(Vec::<FingerprintDiff>::new(), ours.diff(&ours.clone()));
+
+        let theirs = ModlistFingerprint {
+            mods:  vec![("core".to_string(), "0.2.0".parse().unwrap()), ("dark_mode".to_string(), "1.0.0".parse().unwrap())],
+            files: BTreeMap::from([("palettes/default.palette.ron".to_string(), 3), ("dark_mode/main.lua".to_string(), 4)]),
+        };
+        assert_ne!(ours.hash(), theirs.hash());
+        assert_eq!(vec![
+            FingerprintDiff::ModVersion { name: "core".to_string(), ours: "0.1.0".parse().unwrap(), theirs: "0.2.0".parse().unwrap() },
+            FingerprintDiff::MissingMod { name: "editor".to_string(), version: "0.1.0".parse().unwrap() },
+            FingerprintDiff::ExtraMod { name: "dark_mode".to_string(), version: "1.0.0".parse().unwrap() },
+            FingerprintDiff::MissingFile { path: "editor/main.lua".to_string() },
+            FingerprintDiff::FileContent { path: "palettes/default.palette.ron".to_string() },
+            FingerprintDiff::ExtraFile { path: "dark_mode/main.lua".to_string() },
+        ], ours.diff(&theirs));
+
+        let mut reordered = ours.clone();
+        reordered.mods.reverse();
+        assert_eq!(vec![
+            FingerprintDiff::LoadOrder { ours: vec!["core".to_string(), "editor".to_string()], theirs: vec!["editor".to_string(), "core".to_string()] },
+        ], ours.diff(&reordered));
+    }
+
+    #[test]
+    fn fingerprint_files() {
+        let root = std::env::temp_dir().join(format!("bifrons_fingerprint_{}", std::process::id()));
+        fs::create_dir_all(root.join("assets/textures")).unwrap();
+        fs::create_dir_all(root.join("retexture/textures")).unwrap();
+        fs::write(root.join("assets/textures/wall.png"), "core wall").unwrap();
+        fs::write(root.join("assets/textures/floor.png"), "core floor").unwrap();
+        fs::write(root.join("retexture/textures/wall.png"), "mod wall").unwrap();
+
+        let overrides = HashMap::from([(PathBuf::from("textures/wall.png"), VirtualFile::File(root.join("retexture/textures/wall.png")))]);
+        let fingerprint = ModlistFingerprint::new(Vec::new(), &overrides, &root.join("assets")).unwrap();
+        assert_eq!(BTreeMap::from([
+            ("textures/floor.png".to_string(), stable_hash(b"core floor")),
+            ("textures/wall.png".to_string(),  stable_hash(b"mod wall")),
+        ]), fingerprint.files);
+
+        // a peer whose core files differ doesn't match, even though no mod touches them
+        fs::write(root.join("assets/textures/floor.png"), "edited floor").unwrap();
+        let edited = ModlistFingerprint::new(Vec::new(), &overrides, &root.join("assets")).unwrap();
+        assert_eq!(vec![FingerprintDiff::FileContent { path: "textures/floor.png".to_string() }], fingerprint.diff(&edited));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -8,7 +8,7 @@ use parking_lot::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{scripting::{time::LuaTime, bevy_api::{LuaEntity, math::{LuaVec2, LuaVec3}, handle::LuaHandle}, lua_to_string, LuaMod, ui::text::TextBuilder}, system::common::fix_missing_extension};
+use crate::{scripting::{diagnostics::{profiled_call, report_error}, sandbox::budgeted, wait::{call_resumable, resume_waiting, WaitUntil}, message::ReplyTo, time::LuaTime, bevy_api::{LuaEntity, math::{LuaVec2, LuaVec3}, handle::LuaHandle}, lua_to_string, LuaMod, ui::text::TextBuilder}, system::common::fix_missing_extension};
 
 use super::{palette::{DynColor}, rgba::RgbaColor};
 
@@ -138,16 +138,25 @@ pub struct Hook {
 }
 impl Hook {
     pub fn exec<'lua>(&self, lua: &'lua RwLock<Lua>, entity: LuaEntity) -> Result<(), LuaError> {
+        self.exec_replying(lua, entity, None)
+    }
+
+    /// Calls the hook, sending what it returns to `reply_to` once it finishes
+    pub fn exec_replying<'lua>(&self, lua: &'lua RwLock<Lua>, entity: LuaEntity, reply_to: Option<ReplyTo>) -> Result<(), LuaError> {
         let lua = lua.write();
+        // coroutines waiting on this hook are profiled and budgeted on their own as they resume
+        resume_waiting(&lua, |until| matches!(until, WaitUntil::Message(name) if *name == self.name), self.args.clone())?;
         lua.globals().set("entity", entity)?;
-        if let Some(f) = lua.globals().get::<_, Option<LuaFunction>>(self.name.clone())? {
-            f.call(self.args.clone())?;
+        match lua.globals().get::<_, Option<LuaFunction>>(self.name.clone())? {
+            Some(f) => profiled_call(&lua, &f, &self.name, Some(entity.0), || budgeted(&lua, || {
+                call_resumable(&lua, f.clone(), self.args.clone(), Some(entity.0), reply_to)
+            })),
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    pub fn log_err(&self, err: LuaError) {
-        error!("{} threw {}", self.name, err);
+    pub fn log_err(&self, lua: &Lua, entity: LuaEntity, err: LuaError) {
+        report_error(lua, &self.name, Some(entity.0), &err);
     }
 }
 
@@ -155,6 +164,12 @@ impl Hook {
 pub enum Recipient {
     Entity(Entity),
     Script(String),
+    /// Every entity with this tag in its [`Tags`](super::prefab::Tags)
+    Tag(String),
+    /// Every entity [`InRoom`](super::level::InRoom) with this name
+    Room(String),
+    /// The children of this entity
+    Children(Entity),
     Everyone,
     NoOne,
 }
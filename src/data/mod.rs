@@ -1,5 +1,6 @@
 pub mod anim;
 pub mod assetio;
+pub mod fingerprint;
 pub mod formlist;
 pub mod geometry;
 pub mod input;
@@ -10,6 +11,7 @@ pub mod lang;
 pub mod material;
 pub mod module;
 pub mod palette;
+pub mod patch;
 pub mod prefab;
 pub mod rgba;
 pub mod setting;
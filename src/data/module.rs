@@ -1,20 +1,69 @@
 // it's not a mod, cause that's a reserved keyword
 
-use std::{collections::{HashMap, HashSet}, path::Path, cmp::Ordering};
+use std::{collections::{HashMap, HashSet}, path::Path, cmp::Ordering, fmt, str::FromStr};
 
 use bevy::{asset::{AssetLoader, LoadedAsset}, reflect::TypeUuid, prelude::{Component}};
 use indexmap::IndexMap;
-use serde::{Deserialize, Serialize, Deserializer, de, };
+use serde::{Deserialize, Serialize, Deserializer, Serializer, de::{self, Visitor, SeqAccess, MapAccess}};
 
 use crate::util::{ron_options, Roughly};
 
-use super::lua::ScriptVar;
+use super::{assetio::is_zip_mod, lua::ScriptVar, setting::SettingDef};
 
-#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Version(u64, u64, u64);
 impl Default for Version {
     fn default() -> Self { Version(0, 1, 0) }
 }
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split('.').collect();
+        if parts.len() != 3 {
+            return Err(format!("Version `{}` should have exactly 3 parts, as in major.minor.patch", s));
+        }
+        let part = |i: usize| parts[i].trim().parse::<u64>()
+            .map_err(|_| format!("Version `{}` has invalid part `{}`; expected a whole number", s, parts[i]));
+        Ok(Version(part(0)?, part(1)?, part(2)?))
+    }
+}
+impl Serialize for Version {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        s.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for Version {
+    /// Accepts either `"1.2.3"` or the tuple form `(1, 2, 3)`
+    fn deserialize<D>(d: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        struct VersionVisitor;
+        impl<'de> Visitor<'de> for VersionVisitor {
+            type Value = Version;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a version string like \"1.2.3\" or a tuple like (1, 2, 3)")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: de::Error {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: SeqAccess<'de> {
+                let major = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let minor = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let patch = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(Version(major, minor, patch))
+            }
+        }
+        d.deserialize_any(VersionVisitor)
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub enum VersionBound {
     #[default]
@@ -35,8 +84,51 @@ impl VersionBound {
         }
     }
 }
+impl fmt::Display for VersionBound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionBound::Any           => write!(f, "*"),
+            VersionBound::AtLeast(n)    => write!(f, "^{}", n),
+            VersionBound::Between(a, b) => write!(f, "{}-{}", a, b),
+            VersionBound::Exactly(n)    => write!(f, "{}", n),
+            VersionBound::Outside(a, b) => write!(f, "!{}-{}", a, b),
+        }
+    }
+}
+impl FromStr for VersionBound {
+    type Err = String;
 
-#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+    /// `*` for any, `n` for exactly, `^n` for at least, `a-b` for between, and `!a-b` for outside
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let num = |n: &str| n.trim().parse::<u64>()
+            .map_err(|_| format!("Version bound `{}` has invalid number `{}`", s, n));
+        let range = |r: &str| match r.split_once('-') {
+            Some((a, b)) => {
+                let (a, b) = (num(a)?, num(b)?);
+                if a <= b { Ok((a, b)) } else { Err(format!("Version bound `{}` has a range whose start is greater than its end", s)) }
+            },
+            None => Err(format!("Version bound `{}` is missing the `-` in its range", s)),
+        };
+        if s == "*" {
+            Ok(VersionBound::Any)
+        } else if let Some(n) = s.strip_prefix('^') {
+            Ok(VersionBound::AtLeast(num(n)?))
+        } else if let Some(r) = s.strip_prefix('!') {
+            let (a, b) = range(r)?;
+            Ok(VersionBound::Outside(a, b))
+        } else if s.contains('-') {
+            let (a, b) = range(s)?;
+            Ok(VersionBound::Between(a, b))
+        } else if s.is_empty() {
+            Err("Version bound is empty; expected one of `*`, `n`, `^n`, `a-b`, or `!a-b`".to_string())
+        } else {
+            Ok(VersionBound::Exactly(num(s)?))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
 pub enum VersionDependency {
     #[default]
     Any,
@@ -50,6 +142,84 @@ impl VersionDependency {
         }
     } 
 }
+impl fmt::Display for VersionDependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionDependency::Any => write!(f, "*"),
+            VersionDependency::Versioned(maj, min, fix) => write!(f, "{}.{}.{}", maj, min, fix),
+        }
+    }
+}
+impl FromStr for VersionDependency {
+    type Err = String;
+
+    /// Parses strings like `*`, `0.0.*`, `^1.^6.*`, or `2.3-5.*`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "*" || s == "Any" {
+            return Ok(VersionDependency::Any);
+        }
+        let parts: Vec<&str> = s.split('.').collect();
+        if parts.len() != 3 {
+            return Err(format!("Version dependency `{}` should be `*` or have exactly 3 parts, as in major.minor.patch", s));
+        }
+        let bound = |i: usize| parts[i].parse::<VersionBound>()
+            .map_err(|e| format!("In version dependency `{}`: {}", s, e));
+        Ok(VersionDependency::Versioned(bound(0)?, bound(1)?, bound(2)?))
+    }
+}
+impl Serialize for VersionDependency {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        s.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for VersionDependency {
+    /// Accepts the compact string notation, or the enum form such as `Versioned(Exactly(0), AtLeast(3), Any)`
+    /// 
+    /// Ron's deserialize_any drops variant names, but `Any` is the only unit variant and `Versioned` the only one with fields,
+    /// so the visited shape is enough to tell them apart; the bounds themselves still go through their derived impl
+    fn deserialize<D>(d: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        struct VersionDependencyVisitor;
+        impl<'de> Visitor<'de> for VersionDependencyVisitor {
+            type Value = VersionDependency;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a version dependency string like \"^1.^6.*\", `Any`, or `Versioned(..)`")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: de::Error {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_unit<E>(self) -> Result<Self::Value, E> where E: de::Error {
+                Ok(VersionDependency::Any)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: SeqAccess<'de> {
+                let major = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let minor = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let patch = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(VersionDependency::Versioned(major, minor, patch))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: MapAccess<'de> {
+                match map.next_key::<String>()?.as_deref() {
+                    Some("Versioned") => {
+                        let (major, minor, patch) = map.next_value()?;
+                        Ok(VersionDependency::Versioned(major, minor, patch))
+                    },
+                    Some("Any") => {
+                        map.next_value::<de::IgnoredAny>()?;
+                        Ok(VersionDependency::Any)
+                    },
+                    Some(other) => Err(de::Error::unknown_variant(other, &["Any", "Versioned"])),
+                    None        => Err(de::Error::invalid_length(0, &self)),
+                }
+            }
+        }
+        d.deserialize_any(VersionDependencyVisitor)
+    }
+}
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ModError {
@@ -57,6 +227,12 @@ pub enum ModError {
         circular:    String,
         parent:      Option<String>,
     },
+    Conflict {
+        name:        String,
+        version:     Version,
+        banned:      VersionDependency,
+        banned_by:   String,
+    },
     IncompatibleVersion {
         name:        String,
         version:     Version,
@@ -67,8 +243,43 @@ pub enum ModError {
         name:        String,
         required_by: Option<String>,
     },
+    DisabledDependency {
+        name:        String,
+        dependency:  String,
+    },
+}
+impl ModError {
+    /// The name of the mod that should be disabled to resolve this error
+    pub fn at_fault(&self) -> &String {
+        match self {
+            ModError::CircularDependency { circular, .. }          => circular,
+            ModError::Conflict { banned_by, .. }                   => banned_by,
+            ModError::IncompatibleVersion { name, required_by, .. } => required_by.as_ref().unwrap_or(name),
+            ModError::MissingDependency { name, required_by }      => required_by.as_ref().unwrap_or(name),
+            ModError::DisabledDependency { name, .. }              => name,
+        }
+    }
+}
+impl fmt::Display for ModError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModError::CircularDependency { circular, parent: Some(parent) } =>
+                write!(f, "Mod `{}` depends on itself through `{}`", circular, parent),
+            ModError::CircularDependency { circular, parent: None } =>
+                write!(f, "Mod `{}` depends on itself", circular),
+            ModError::Conflict { name, version, banned, banned_by } =>
+                write!(f, "Mod `{}` conflicts with `{}` at versions {}, but version {} is installed", banned_by, name, banned, version),
+            ModError::IncompatibleVersion { name, version, allowed, required_by } =>
+                write!(f, "Mod `{}` requires `{}` at versions {}, but version {} is installed", required_by.as_deref().unwrap_or("?"), name, allowed, version),
+            ModError::MissingDependency { name, required_by } =>
+                write!(f, "Mod `{}` requires `{}`, which is not installed", required_by.as_deref().unwrap_or("?"), name),
+            ModError::DisabledDependency { name, dependency } =>
+                write!(f, "Mod `{}` was disabled because it depends on `{}`", name, dependency),
+        }
+    }
 }
 
+/// A mod in the load order, and the directory or zip archive its files come from
 #[derive(Clone, Component, Debug, PartialEq)]
 pub struct LoadEntry {
     pub name: String,
@@ -83,68 +294,167 @@ pub struct Module {
     pub priority:        f64,
     #[serde(default)]
     pub dependencies:    HashMap<String, VersionDependency>,
-    // #[serde(default)]
-    // pub banned:          HashMap<String, VersionDependency>,
+    /// Mods that this one loads after when they are installed, but which aren't required for it to work
+    #[serde(default)]
+    pub optional_dependencies: HashMap<String, VersionDependency>,
+    /// Mods that are known to break this one when loaded at any version matching the given range
+    #[serde(default, alias = "banned")]
+    pub conflicts:       HashMap<String, VersionDependency>,
     #[serde(default)]
     pub lines:           HashMap<String, HashMap<String, String>>,
     #[serde(default)]
     pub startup_scripts: Vec<String>,
     #[serde(default)]
     pub permissions:     HashSet<String>,
+    /// The schema for settings players can set in this mod's modlist entry
+    #[serde(default)]
+    pub settings:        IndexMap<String, SettingDef>,
 }
 impl Module {
     pub fn is_version_valid(&self, version: VersionDependency) -> bool {
         version.is_valid(self.version)
     }
 
+    /// Checks that every setting's default fits its own schema
+    pub fn validate_settings(&self) -> Result<(), String> {
+        for (name, def) in self.settings.iter() {
+            def.validate(&def.default).map_err(|e| format!("Default for setting `{}` is invalid: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the values for every declared setting, using the default for any missing or invalid value
+    /// 
+    /// Returns the resolved settings, along with a message for each value that didn't fit the schema
+    pub fn resolve_settings(&self, values: &HashMap<String, ScriptVar>) -> (HashMap<String, ScriptVar>, Vec<String>) {
+        let mut errors = Vec::new();
+        let resolved = self.settings.iter()
+            .map(|(name, def)| (name.clone(), match values.get(name) {
+                Some(value) => def.validate(value).unwrap_or_else(|e| {
+                    errors.push(format!("Setting `{}` is invalid, so its default will be used: {}", name, e));
+                    def.default.clone()
+                }),
+                None => def.validate(&def.default).unwrap_or_else(|_| def.default.clone()),
+            }))
+            .collect();
+        let mut unknown: Vec<&String> = values.keys().filter(|k| !self.settings.contains_key(*k)).collect();
+        unknown.sort();
+        errors.extend(unknown.into_iter().map(|k| format!("Setting `{}` is not declared by this mod, so it will be ignored", k)));
+        (resolved, errors)
+    }
+
+    /// Returns the first conflict declared by any of the given mods against another one in the list
+    /// 
+    /// Mods are checked in name order so the same modlist always reports the same conflict
+    pub fn check_conflicts(mods: &HashMap<String, &Module>) -> Result<(), ModError> {
+        let mut names: Vec<&String> = mods.keys().collect();
+        names.sort();
+        for name in names {
+            let mut conflicts: Vec<(&String, &VersionDependency)> = mods[name].conflicts.iter().collect();
+            conflicts.sort();
+            for (other_name, banned) in conflicts {
+                if let Some(other) = mods.get(other_name) && other.is_version_valid(*banned) {
+                    return Err(ModError::Conflict {
+                        name:      other_name.clone(),
+                        version:   other.version,
+                        banned:    *banned,
+                        banned_by: name.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `sorted_load_order`, but instead of stopping at the first error this disables the mod at fault
+    /// and every mod depending on it, then tries again with what's left.
+    /// 
+    /// Returns the load order of the remaining mods, along with the errors for each disabled mod.
+    pub fn recovered_load_order(mods: &HashMap<String, &Module>) -> (Vec<Vec<String>>, Vec<ModError>) {
+        fn disable(mods: &mut HashMap<String, &Module>, name: &String, errors: &mut Vec<ModError>) -> bool {
+            if mods.remove(name).is_none() {
+                return false;
+            }
+            let mut dependents: Vec<String> = mods.iter()
+                .filter(|(_, m)| m.dependencies.contains_key(name))
+                .map(|(n, _)| n.clone())
+                .collect();
+            dependents.sort();
+            for dependent in dependents {
+                if mods.contains_key(&dependent) {
+                    errors.push(ModError::DisabledDependency { name: dependent.clone(), dependency: name.clone() });
+                    disable(mods, &dependent, errors);
+                }
+            }
+            true
+        }
+
+        let mut remaining = mods.clone();
+        let mut errors    = Vec::new();
+        loop {
+            let result = Module::sorted_load_order(&remaining)
+                .map(|order| order.into_iter().map(|wave| wave.into_iter().cloned().collect()).collect());
+            match result {
+                Ok(order) => return (order, errors),
+                Err(err)  => {
+                    let at_fault = err.at_fault().clone();
+                    errors.push(err);
+                    if !disable(&mut remaining, &at_fault, &mut errors) {
+                        return (Vec::new(), errors);
+                    }
+                },
+            }
+        }
+    }
+
     /// Sorts a map of all loaded modules, and returns a Vec of a Vec of mod names.
     /// The outer Vec represents waves of mods that can be loaded in parallel, 
     /// and the inner vec are those mod names, sorted by mod priority.
     /// 
-    /// todo: Allow mods to move into later waves depending on priority order. Current impl had issues with moving into waves where mods that depend on a high priority one are.
+    /// Optional dependencies are treated like normal ones when that mod is present, and are otherwise ignored.
+    /// 
+    /// Mods load in priority order as long as their dependencies are met, so a mod may be pushed into a later wave
+    /// than its dependencies need to make sure it overrides the files of every lower priority mod.
     pub fn sorted_load_order<'a, 'b>(mods: &'b HashMap<String, &'a Module>) -> Result<Vec<Vec<&'a String>>, ModError> where 'b: 'a {
         if mods.is_empty() {
             return Ok(Vec::new());
         }
+        Module::check_conflicts(mods)?;
 
         #[derive(Clone, Debug, PartialEq)]
-        struct Wave<'a> {
-            index:    usize,
+        struct Scheduled<'a> {
             name:     &'a String,
             priority: f64,
             requires: Vec<&'a String>,
         }
-        impl<'a> Eq for Wave<'a> {}
-        impl<'a> Ord for Wave<'a> {
+        impl<'a> Eq for Scheduled<'a> {}
+        impl<'a> Ord for Scheduled<'a> {
             fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-                let i = self.index.cmp(&other.index);
-                if i == Ordering::Equal {
-                    let p = Roughly(self.priority).cmp(&Roughly(other.priority));
-                    if p == Ordering::Equal {
-                        self.name.cmp(other.name)
-                    } else { p }
-                } else { i }
+                let p = Roughly(self.priority).cmp(&Roughly(other.priority));
+                if p == Ordering::Equal {
+                    self.name.cmp(other.name)
+                } else { p }
             }
         }
-        impl<'a> PartialOrd for Wave<'a> {
+        impl<'a> PartialOrd for Scheduled<'a> {
             fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
                 Some(self.cmp(other))
             }
         }
         #[derive(Clone, Debug, PartialEq, PartialOrd)]
-        enum WaveProgress<'a> { Done(Wave<'a>), Loading }
-        let mut waves = HashMap::<String, WaveProgress>::new();
+        enum Progress<'a> { Done(Scheduled<'a>), Loading }
+        let mut progress = HashMap::<String, Progress>::new();
 
         fn visit<'a>(
-            waves:   &mut HashMap<String, WaveProgress<'a>>,
-            mods:    &'a HashMap<String, &Module>,
-            name:    &'a String,
-            parent:  Option<&String>,
-            version: Option<VersionDependency>,
-        ) -> Result<usize, ModError> {
-            match waves.get(name) {
-                Some(WaveProgress::Done(i)) => Ok(i.index),
-                Some(WaveProgress::Loading)  => Err(ModError::CircularDependency { circular: name.clone(), parent: parent.cloned() }),
+            progress: &mut HashMap<String, Progress<'a>>,
+            mods:     &'a HashMap<String, &Module>,
+            name:     &'a String,
+            parent:   Option<&String>,
+            version:  Option<VersionDependency>,
+        ) -> Result<(), ModError> {
+            match progress.get(name) {
+                Some(Progress::Done(_)) => Ok(()),
+                Some(Progress::Loading) => Err(ModError::CircularDependency { circular: name.clone(), parent: parent.cloned() }),
                 None => {
                     let module = *mods.get(name)
                         .ok_or_else(|| ModError::MissingDependency { name: name.clone(), required_by: parent.cloned() })?;
@@ -154,46 +464,72 @@ impl Module {
                             return Err(ModError::IncompatibleVersion { name: name.clone(), version: module.version, allowed: version, required_by: parent.cloned() })
                         }
                     }
-                    let mut wave = 0usize;
-                    waves.insert(name.clone(), WaveProgress::Loading);
-                    for (dep_name, dep_version) in module.dependencies.iter() {
-                        wave = wave.max(visit(waves, mods, dep_name, Some(name), Some(*dep_version))?);
-                        if let WaveProgress::Done(w) = waves.get_mut(dep_name).unwrap() {
-                            w.requires.push(name);
-                        } else { unreachable!() }
+                    let mut requires = Vec::new();
+                    progress.insert(name.clone(), Progress::Loading);
+                    let present_optionals = module.optional_dependencies.iter()
+                        .filter(|(dep_name, _)| mods.contains_key(*dep_name));
+                    for (dep_name, dep_version) in module.dependencies.iter().chain(present_optionals) {
+                        visit(progress, mods, dep_name, Some(name), Some(*dep_version))?;
+                        if !requires.contains(&dep_name) {
+                            requires.push(dep_name);
+                        }
                     }
-                    waves.insert(name.clone(), WaveProgress::Done(Wave {
+                    progress.insert(name.clone(), Progress::Done(Scheduled {
                         name:     &name,
-                        index:    wave + 1,
                         priority: module.priority,
-                        requires: Vec::new(),
+                        requires,
                     }));
-                    Ok(wave + 1)
+                    Ok(())
                 },
             }
         }
         for name in mods.keys() {
-            visit(&mut waves, mods, name, None, None)?;
-        }
-
-        let mut waves_im: IndexMap<usize, Vec<Wave>> = waves.into_iter()
-            .fold(IndexMap::new(), |mut acc, (_, wp)| {
-                let wave = match wp {
-                    WaveProgress::Done(w) => w,
-                    _ => { unreachable!() }
-                };
-                let priorities = acc.entry(wave.index).or_insert_with(Vec::new);
-                priorities.push(wave);
-                acc
-            });
-        waves_im.sort_keys();
-        for vec in waves_im.values_mut() {
-            vec.sort();
-        }
-        
-        Ok(waves_im.into_iter()
-            .map(|(_, v)| v.into_iter().map(|w| w.name).collect())
-            .collect())
+            visit(&mut progress, mods, name, None, None)?;
+        }
+
+        let scheduled: Vec<Scheduled> = progress.into_values()
+            .map(|p| match p {
+                Progress::Done(s) => s,
+                _ => { unreachable!() }
+            })
+            .collect();
+        let mut required_by = HashMap::<&String, Vec<&Scheduled>>::new();
+        let mut waiting_on  = HashMap::<&String, usize>::new();
+        let mut ready       = Vec::<&Scheduled>::new();
+        for s in scheduled.iter() {
+            for dep in s.requires.iter() {
+                required_by.entry(*dep).or_default().push(s);
+            }
+            waiting_on.insert(s.name, s.requires.len());
+            if s.requires.is_empty() {
+                ready.push(s);
+            }
+        }
+
+        // Always take the lowest priority mod whose dependencies are loaded, so later (higher priority) mods override its files.
+        // A mod goes in the same wave as the last one unless it depends on something there, keeping waves in that same order
+        let mut wave_of = HashMap::<&String, usize>::new();
+        let mut waves   = Vec::<Vec<&'a String>>::new();
+        while let Some(i) = ready.iter().enumerate().min_by(|(_, a), (_, b)| a.cmp(b)).map(|(i, _)| i) {
+            let next = ready.swap_remove(i);
+            let wave = next.requires.iter()
+                .map(|dep| wave_of[dep] + 1)
+                .fold(waves.len().saturating_sub(1), usize::max);
+            if wave == waves.len() {
+                waves.push(Vec::new());
+            }
+            waves[wave].push(next.name);
+            wave_of.insert(next.name, wave);
+
+            for dependent in required_by.get(next.name).into_iter().flatten() {
+                let waiting = waiting_on.get_mut(dependent.name).unwrap();
+                *waiting -= 1;
+                if *waiting == 0 {
+                    ready.push(*dependent);
+                }
+            }
+        }
+        Ok(waves)
     }
 }
 
@@ -210,17 +546,23 @@ impl AssetLoader for ModuleLoader {
         ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
             Box::pin(async move {
                 let module: Module = ron_options().from_bytes(bytes)?;
+                module.validate_settings().map_err(bevy::asset::Error::msg)?;
                 load_context.set_default_asset(LoadedAsset::new(module));
                 Ok(())
             })
     }
 }
 
+/// An entry in the modlist, where `file` is either a `.mod.ron` in the mod's root directory,
+/// or a `.zip` archive holding the mod's files with a `<name>.mod.ron` at its root
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct ModEntry {
     pub file:     String,
     #[serde(default)]
     pub settings: HashMap<String, ScriptVar>,
+    /// Disabled entries stay in the modlist with their settings, but aren't loaded
+    #[serde(skip_serializing_if = "ModEntry::is_enabled")]
+    pub enabled:  bool,
     #[serde(skip)]
     pub name:     String,
 }
@@ -231,6 +573,8 @@ impl<'de> Deserialize<'de> for ModEntry {
             pub file:     String,
             #[serde(default)]
             pub settings: HashMap<String, ScriptVar>,
+            #[serde(default = "ModEntry::enabled_by_default")]
+            pub enabled:  bool,
         }
         let m: ModEntryDe = Deserialize::deserialize(d)?;
 
@@ -238,13 +582,47 @@ impl<'de> Deserialize<'de> for ModEntry {
         if !path.is_file() && path.extension().map(|e| e.eq_ignore_ascii_case("mod.ron")).unwrap_or(false) {
             return Err(de::Error::custom("ModEntry `file` is not a valid .mod.ron file"))
         }
-        let name = Path::new(&m.file)
+        let name = ModEntry::name_from_file(&m.file).ok_or_else(|| {
+            de::Error::custom("ModEntry `file` does not point to a .mod.ron or .zip file")
+        })?;
+        Ok(ModEntry { file: m.file, settings: m.settings, enabled: m.enabled, name })
+    }
+}
+
+impl ModEntry {
+    /// An enabled entry with default settings, or `None` if `file` isn't a `.mod.ron` or `.zip` file
+    pub fn new(file: String) -> Option<ModEntry> {
+        let name = ModEntry::name_from_file(&file)?;
+        Some(ModEntry { file, settings: HashMap::new(), enabled: true, name })
+    }
+
+    /// Mods are named after their `.mod.ron` or `.zip` file
+    pub fn name_from_file(file: &str) -> Option<String> {
+        Path::new(file)
             .file_name()
-            .map(|s| s.to_string_lossy().strip_suffix(".mod.ron").unwrap().to_string())
-            .ok_or_else(|| {
-                de::Error::custom("ModEntry `file` does not point to a file")
-            })?;
-        Ok(ModEntry { file: m.file, settings: m.settings, name })
+            .and_then(|s| {
+                let s = s.to_string_lossy();
+                s.strip_suffix(".mod.ron")
+                    .or_else(|| s.strip_suffix(".zip"))
+                    .map(|n| n.to_string())
+            })
+    }
+
+    fn enabled_by_default() -> bool { true }
+
+    fn is_enabled(enabled: &bool) -> bool { *enabled }
+
+    pub fn is_zip(&self) -> bool {
+        is_zip_mod(&self.file)
+    }
+
+    /// The path given to the asset server to load this mod's `.mod.ron`, relative to the assets directory
+    pub fn mod_def_path(&self) -> String {
+        if self.is_zip() {
+            format!("../{}/{}.mod.ron", self.file, self.name)
+        } else {
+            format!("../{}", self.file)
+        }
     }
 }
 
@@ -255,9 +633,11 @@ pub struct ModList {
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::{HashMap}, default::default};
+    use std::{collections::{HashMap}, default::default, path::Path, sync::Arc};
 
-    use crate::{data::module::{VersionBound, Version, ModError}, util::collections::Singleton};
+    use parking_lot::RwLock;
+
+    use crate::{data::{module::{VersionBound, Version, ModError, LoadEntry}, assetio::VirtualFileOverrides, lua::ScriptVar}, util::{collections::Singleton, ron_options}};
 
     use super::{VersionDependency, Module};
 
@@ -308,6 +688,271 @@ mod tests {
         }
     }
 
+    #[test]
+    fn version_dependency_from_str() {
+        assert_eq!(Ok(VersionDependency::Any), "*".parse());
+        assert_eq!(Ok(VersionDependency::Versioned(VersionBound::Exactly(0), VersionBound::Exactly(0), VersionBound::Any)), "0.0.*".parse());
+        assert_eq!(Ok(VersionDependency::Versioned(VersionBound::AtLeast(1), VersionBound::AtLeast(6), VersionBound::Any)), "^1.^6.*".parse());
+        assert_eq!(Ok(VersionDependency::Versioned(VersionBound::Exactly(2), VersionBound::Between(3, 5), VersionBound::Any)), "2.3-5.*".parse());
+        assert_eq!(Ok(VersionDependency::Versioned(VersionBound::Outside(1, 2), VersionBound::Any, VersionBound::Any)), "!1-2.*.*".parse());
+
+        for vs in ["0.0.*", "^1.^6.*", "2.3-5.*", "!1-2.*.*", "*"] {
+            assert_eq!(vs, vs.parse::<VersionDependency>().unwrap().to_string());
+        }
+
+        assert!("1.2".parse::<VersionDependency>().is_err());
+        assert!("1.x.*".parse::<VersionDependency>().is_err());
+        assert!("1.5-3.*".parse::<VersionDependency>().is_err());
+        assert!("1..*".parse::<VersionDependency>().is_err());
+
+        assert_eq!(Ok(Version(1, 20, 3)), "1.20.3".parse());
+        assert!("1.20".parse::<Version>().is_err());
+        assert!("1.*.3".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn version_dependency_ron() {
+        let from_ron = |s: &str| ron_options().from_str::<VersionDependency>(s);
+
+        assert_eq!(Ok(VersionDependency::Any), from_ron("Any"));
+        assert_eq!(Ok(VersionDependency::Any), from_ron("\"*\""));
+        assert_eq!(
+            Ok(VersionDependency::Versioned(VersionBound::Exactly(0), VersionBound::AtLeast(3), VersionBound::Any)),
+            from_ron("Versioned(Exactly(0), AtLeast(3), Any)"),
+        );
+        assert_eq!(
+            Ok(VersionDependency::Versioned(VersionBound::Exactly(0), VersionBound::AtLeast(3), VersionBound::Any)),
+            from_ron("\"0.^3.*\""),
+        );
+        assert!(from_ron("\"0.^x.*\"").is_err());
+
+        assert_eq!(Ok(Version(0, 1, 0)), ron_options().from_str::<Version>("(0,1,0)"));
+        assert_eq!(Ok(Version(0, 1, 0)), ron_options().from_str::<Version>("\"0.1.0\""));
+
+        let vd = VersionDependency::Versioned(VersionBound::Exactly(2), VersionBound::Between(3, 5), VersionBound::Any);
+        assert_eq!(Ok("\"2.3-5.*\"".to_string()), ron::to_string(&vd));
+        assert_eq!(Ok("\"0.16.4\"".to_string()), ron::to_string(&Version(0, 16, 4)));
+    }
+
+    #[test]
+    fn conflicting_mods() {
+        let core = Module {
+            version: Version(1, 4, 0),
+            ..default()
+        };
+        let old_patch = Module {
+            dependencies: HashMap::singleton(("core".to_string(), VersionDependency::Any)),
+            conflicts:    HashMap::singleton(("core".to_string(), "^1.^3.*".parse().unwrap())),
+            ..default()
+        };
+        let new_patch = Module {
+            dependencies: HashMap::singleton(("core".to_string(), VersionDependency::Any)),
+            conflicts:    HashMap::singleton(("old_patch".to_string(), VersionDependency::Any)),
+            ..default()
+        };
+
+        let mut mods: HashMap<String, &Module> = HashMap::new();
+        mods.insert("core".to_string(), &core);
+        mods.insert("new_patch".to_string(), &new_patch);
+        assert!(Module::sorted_load_order(&mods).is_ok(), "conflicts with missing mods are ignored");
+
+        mods.insert("old_patch".to_string(), &old_patch);
+        assert_eq!(
+            Err(ModError::Conflict { name: "old_patch".to_string(), version: Version(0, 1, 0), banned: VersionDependency::Any, banned_by: "new_patch".to_string() }),
+            Module::sorted_load_order(&mods)
+        );
+
+        mods.remove("new_patch");
+        assert_eq!(
+            Err(ModError::Conflict { name: "core".to_string(), version: Version(1, 4, 0), banned: "^1.^3.*".parse().unwrap(), banned_by: "old_patch".to_string() }),
+            Module::sorted_load_order(&mods)
+        );
+
+        let core = Module { version: Version(1, 2, 0), ..default() };
+        mods.insert("core".to_string(), &core);
+        assert!(Module::sorted_load_order(&mods).is_ok(), "conflicts outside of the banned range are allowed");
+    }
+
+    #[test]
+    fn sorted_mod_list_optional() {
+        let core_name = "core".to_string();
+        let core = Module {
+            priority: -99999999.,
+            ..default()
+        };
+        let big_overhaul_name = "big_overhaul".to_string();
+        let big_overhaul = Module {
+            version:      Version(2, 0, 0),
+            dependencies: HashMap::singleton(("core".to_string(), VersionDependency::Any)),
+            ..default()
+        };
+        let compat_patch_name = "compat_patch".to_string();
+        let compat_patch = Module {
+            dependencies:          HashMap::singleton(("core".to_string(), VersionDependency::Any)),
+            optional_dependencies: HashMap::singleton(("big_overhaul".to_string(), "^2.*.*".parse().unwrap())),
+            priority:              -10.,
+            ..default()
+        };
+
+        let mut mods: HashMap<String, &Module> = HashMap::new();
+        mods.insert(core_name.clone(), &core);
+        mods.insert(compat_patch_name.clone(), &compat_patch);
+        assert_eq!(
+            Ok(vec![vec![&core_name], vec![&compat_patch_name]]),
+            Module::sorted_load_order(&mods)
+        );
+
+        mods.insert(big_overhaul_name.clone(), &big_overhaul);
+        assert_eq!(
+            Ok(vec![vec![&core_name], vec![&big_overhaul_name], vec![&compat_patch_name]]),
+            Module::sorted_load_order(&mods)
+        );
+
+        let old_overhaul = Module { version: Version(1, 5, 0), ..big_overhaul.clone() };
+        mods.insert(big_overhaul_name.clone(), &old_overhaul);
+        assert_eq!(
+            Err(ModError::IncompatibleVersion { name: big_overhaul_name.clone(), version: Version(1, 5, 0), allowed: "^2.*.*".parse().unwrap(), required_by: Some(compat_patch_name.clone()) }),
+            Module::sorted_load_order(&mods)
+        );
+    }
+
+    #[test]
+    fn sorted_mod_list_priority() {
+        let core_name = "core".to_string();
+        let core = Module {
+            priority: -99999999.,
+            ..default()
+        };
+        let base_textures_name = "base_textures".to_string();
+        let base_textures = Module {
+            priority: 0.,
+            ..default()
+        };
+        let hd_textures_name = "hd_textures".to_string();
+        let hd_textures = Module {
+            priority: 50.,
+            ..default()
+        };
+        let texture_patch_name = "texture_patch".to_string();
+        let texture_patch = Module {
+            dependencies: HashMap::singleton(("base_textures".to_string(), VersionDependency::Any)),
+            priority:     5.,
+            ..default()
+        };
+
+        let mut mods: HashMap<String, &Module> = HashMap::new();
+        mods.insert(core_name.clone(), &core);
+        mods.insert(base_textures_name.clone(), &base_textures);
+        mods.insert(hd_textures_name.clone(), &hd_textures);
+        mods.insert(texture_patch_name.clone(), &texture_patch);
+        let load_order = Module::sorted_load_order(&mods).unwrap();
+        assert_eq!(
+            vec![vec![&core_name, &base_textures_name], vec![&texture_patch_name, &hd_textures_name]],
+            load_order,
+            "hd_textures has no dependencies, but should still load after the lower priority texture_patch"
+        );
+
+        let root = std::env::temp_dir().join(format!("bifrons_load_order_{}", std::process::id()));
+        let write_file = |mod_name: &str, file: &str| {
+            let path = root.join(mod_name).join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, mod_name).unwrap();
+        };
+        write_file("base_textures", "textures/wall.png");
+        write_file("base_textures", "textures/floor.png");
+        write_file("texture_patch", "textures/wall.png");
+        write_file("texture_patch", "textures/door.png");
+        write_file("hd_textures", "textures/wall.png");
+
+        let load_order: Vec<Vec<LoadEntry>> = load_order.iter()
+            .map(|wave| wave.iter().map(|name| LoadEntry { name: name.to_string(), root: name.to_string() }).collect())
+            .collect();
+        let mut vfo = VirtualFileOverrides { overrides: Arc::new(RwLock::new(HashMap::new())), zipped_dirs: default(), lua_path: String::new(), providers: HashMap::new() };
+        vfo.populate_files_in(&root, &load_order);
+        let overrides = vfo.overrides.read();
+        let owner = |file: &str| overrides.get(Path::new(file)).map(|p| p.source_path().strip_prefix(&root).unwrap().iter().next().unwrap().to_string_lossy().to_string());
+        assert_eq!(Some("hd_textures".to_string()),   owner("textures/wall.png"));
+        assert_eq!(Some("base_textures".to_string()), owner("textures/floor.png"));
+        assert_eq!(Some("texture_patch".to_string()), owner("textures/door.png"));
+        drop(overrides);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn recovered_mod_list() {
+        let core_name = "core".to_string();
+        let core = Module {
+            version:  Version(0, 2, 0),
+            priority: -99999999.,
+            ..default()
+        };
+        let outdated_name = "outdated".to_string();
+        let outdated = Module {
+            dependencies: HashMap::singleton(("core".to_string(), "0.1.*".parse().unwrap())),
+            ..default()
+        };
+        let outdated_addon_name = "outdated_addon".to_string();
+        let outdated_addon = Module {
+            dependencies: HashMap::singleton(("outdated".to_string(), VersionDependency::Any)),
+            ..default()
+        };
+        let fine_name = "fine".to_string();
+        let fine = Module {
+            dependencies:          HashMap::singleton(("core".to_string(), VersionDependency::Any)),
+            optional_dependencies: HashMap::singleton(("outdated".to_string(), VersionDependency::Any)),
+            ..default()
+        };
+
+        let mut mods: HashMap<String, &Module> = HashMap::new();
+        mods.insert(core_name.clone(), &core);
+        mods.insert(outdated_name.clone(), &outdated);
+        mods.insert(outdated_addon_name.clone(), &outdated_addon);
+        mods.insert(fine_name.clone(), &fine);
+
+        let (order, errors) = Module::recovered_load_order(&mods);
+        assert_eq!(vec![vec![core_name.clone()], vec![fine_name.clone()]], order);
+        assert_eq!(vec![
+            ModError::IncompatibleVersion { name: core_name.clone(), version: Version(0, 2, 0), allowed: "0.1.*".parse().unwrap(), required_by: Some(outdated_name.clone()) },
+            ModError::DisabledDependency { name: outdated_addon_name.clone(), dependency: outdated_name.clone() },
+        ], errors);
+    }
+
+    #[test]
+    fn resolved_settings() {
+        let module: Module = ron_options().from_str(r#"(
+            priority: 0.,
+            settings: {
+                "spawn_rate": (kind: Num, default: Num(1.0), min: Some(0.), max: Some(10.)),
+                "hardcore":   (kind: Bool, default: Bool(false)),
+                "greeting":   (kind: Str, default: Str("hello"), label: Some("greeting_label")),
+            },
+        )"#).unwrap();
+        assert_eq!(Ok(()), module.validate_settings());
+
+        let mut values = HashMap::new();
+        values.insert("spawn_rate".to_string(), ScriptVar::Int(3));
+        values.insert("hardcore".to_string(), ScriptVar::Str("yes".to_string()));
+        values.insert("typo".to_string(), ScriptVar::Bool(true));
+        let (resolved, errors) = module.resolve_settings(&values);
+        assert_eq!(Some(&ScriptVar::Num(3.)), resolved.get("spawn_rate"));
+        assert_eq!(Some(&ScriptVar::Bool(false)), resolved.get("hardcore"));
+        assert_eq!(Some(&ScriptVar::Str("hello".to_string())), resolved.get("greeting"));
+        assert_eq!(2, errors.len(), "{:?}", errors);
+
+        values.insert("spawn_rate".to_string(), ScriptVar::Num(11.));
+        let (resolved, _) = module.resolve_settings(&values);
+        assert_eq!(Some(&ScriptVar::Num(1.)), resolved.get("spawn_rate"));
+
+        let broken: Module = ron_options().from_str(r#"(
+            priority: 0.,
+            settings: {
+                "spawn_rate": (kind: Int, default: Int(-1), min: Some(0.)),
+            },
+        )"#).unwrap();
+        assert!(broken.validate_settings().is_err());
+    }
+
     #[test]
     fn sorted_mod_list() {
         let core_name = "core".to_string();
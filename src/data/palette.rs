@@ -12,6 +12,8 @@ use serde::{de::*, Deserialize, Serialize};
 use crate::data::lua::ScriptVar;
 use crate::scripting::{LuaMod, lua_to_string};
 use crate::scripting::bevy_api::handle::LuaHandle;
+use crate::scripting::diagnostics::profiled_call;
+use crate::scripting::sandbox::budgeted;
 use crate::system::common::{fix_missing_extension};
 use crate::system::lua::SharedInstances;
 use crate::system::palette::LoadingPalette;
@@ -585,7 +587,7 @@ impl ColorCache {
                             lua_readable.with_read(|lua| {
                                 let globals = lua.globals();
                                 if let Some(f) = globals.get::<_, Option<LuaFunction>>(function.as_str()).unwrap() {
-                                    match f.call::<_, RgbaColor>(ManyTransVars(new_params)) {
+                                    match profiled_call(lua, &f, "on_miss", None, || budgeted(lua, || f.call::<_, RgbaColor>(ManyTransVars(new_params)))) {
                                         Ok(c)    => c,
                                         Err(err) => {
                                             warn!("Function for on_miss Fn {} errored: {}", function, err);
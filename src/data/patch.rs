@@ -0,0 +1,530 @@
+use std::fmt;
+
+/// Patch files are named after the asset they patch, as in `palettes/default.palette.ron.patch`
+pub const PATCH_EXTENSION: &str = ".patch";
+
+/// Replaces a value outright instead of merging into it, as in `colors: Replace({ "black": "#000000" })`
+pub const REPLACE: &str = "Replace";
+/// Removes a struct field or map entry, as in `"black": Remove`
+pub const REMOVE: &str = "Remove";
+
+/// The asset a `.ron.patch` file applies to, if it is one
+pub fn patch_target(path: &str) -> Option<&str> {
+    path.strip_suffix(PATCH_EXTENSION).filter(|target| target.ends_with(".ron"))
+}
+
+/// A RON value that keeps what `ron::Value` throws away, like struct and enum variant names,
+/// so it can be written back out for any asset's loader to deserialize
+#[derive(Clone, Debug, PartialEq)]
+pub enum RonNode {
+    /// Numbers, strings, chars, bools, and unit structs or variants, exactly as written
+    Atom(String),
+    List(Vec<RonNode>),
+    Map(Vec<(RonNode, RonNode)>),
+    /// Tuples, tuple structs, and tuple variants like `Some(5)`
+    Tuple {
+        name:  Option<String>,
+        items: Vec<RonNode>,
+    },
+    /// Structs and struct variants like `Quad(w: 1., h: 2.)`
+    Struct {
+        name:   Option<String>,
+        fields: Vec<(String, RonNode)>,
+    },
+}
+impl RonNode {
+    fn directive(&self) -> Option<&str> {
+        match self {
+            RonNode::Atom(a) if a == REMOVE => Some(REMOVE),
+            RonNode::Tuple { name: Some(name), items } if name == REPLACE && items.len() == 1 => Some(REPLACE),
+            _ => None,
+        }
+    }
+
+    /// The value a patch sets when there is nothing to merge it into
+    fn into_value(self) -> Result<RonNode, String> {
+        match self.directive() {
+            Some(REPLACE) => match self {
+                RonNode::Tuple { mut items, .. } => Ok(items.remove(0)),
+                _ => unreachable!(),
+            },
+            Some(_) => Err(format!("`{}` can only be used on struct fields and map entries", REMOVE)),
+            None => Ok(self),
+        }
+    }
+
+    /// Merges maps and structs, appends to lists, and replaces everything else
+    pub fn apply(&mut self, patch: RonNode) -> Result<(), String> {
+        if patch.directive().is_some() {
+            *self = patch.into_value()?;
+            return Ok(());
+        }
+        match (self, patch) {
+            (RonNode::Map(entries), RonNode::Map(patches)) => {
+                for (key, patch) in patches {
+                    let index = entries.iter().position(|(k, _)| *k == key);
+                    match (index, patch.directive()) {
+                        (Some(i), Some(REMOVE)) => { entries.remove(i); },
+                        (None,    Some(REMOVE)) => (),
+                        (Some(i), _)            => entries[i].1.apply(patch)?,
+                        (None,    _)            => entries.push((key, patch.into_value()?)),
+                    }
+                }
+            },
+            (RonNode::Struct { name, fields }, RonNode::Struct { name: patch_name, fields: patches }) if patch_name.is_none() || *name == patch_name => {
+                for (key, patch) in patches {
+                    let index = fields.iter().position(|(k, _)| *k == key);
+                    match (index, patch.directive()) {
+                        (Some(i), Some(REMOVE)) => { fields.remove(i); },
+                        (None,    Some(REMOVE)) => (),
+                        (Some(i), _)            => fields[i].1.apply(patch)?,
+                        (None,    _)            => fields.push((key, patch.into_value()?)),
+                    }
+                }
+            },
+            (RonNode::Tuple { name, items }, RonNode::Tuple { name: patch_name, items: patches }) if *name == patch_name && items.len() == patches.len() => {
+                for (item, patch) in items.iter_mut().zip(patches.into_iter()) {
+                    item.apply(patch)?;
+                }
+            },
+            (RonNode::List(items), RonNode::List(patches)) => {
+                for patch in patches {
+                    items.push(patch.into_value()?);
+                }
+            },
+            (this, patch) => *this = patch.into_value()?,
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for RonNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RonNode::Atom(a)   => write!(f, "{}", a),
+            RonNode::List(items) => {
+                write!(f, "[")?;
+                for item in items {
+                    write!(f, "{}, ", item)?;
+                }
+                write!(f, "]")
+            },
+            RonNode::Map(entries) => {
+                write!(f, "{{")?;
+                for (k, v) in entries {
+                    write!(f, "{}: {}, ", k, v)?;
+                }
+                write!(f, "}}")
+            },
+            RonNode::Tuple { name, items } => {
+                write!(f, "{}(", name.as_deref().unwrap_or_default())?;
+                for item in items {
+                    write!(f, "{}, ", item)?;
+                }
+                write!(f, ")")
+            },
+            RonNode::Struct { name, fields } => {
+                write!(f, "{}(", name.as_deref().unwrap_or_default())?;
+                for (k, v) in fields {
+                    write!(f, "{}: {}, ", k, v)?;
+                }
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+/// A whole RON file, including any `#![enable(...)]` extensions at the top
+#[derive(Clone, Debug, PartialEq)]
+pub struct RonDocument {
+    pub extensions: Vec<String>,
+    pub root:       RonNode,
+}
+impl fmt::Display for RonDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for extension in self.extensions.iter() {
+            writeln!(f, "{}", extension)?;
+        }
+        write!(f, "{}", self.root)
+    }
+}
+impl std::str::FromStr for RonDocument {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { src: s, pos: 0 };
+        let mut extensions = Vec::new();
+        parser.skip_ws()?;
+        while parser.rest().starts_with("#!") {
+            let end = parser.rest().find(']').ok_or_else(|| parser.error("unclosed extension attribute"))?;
+            extensions.push(parser.rest()[..=end].to_string());
+            parser.pos += end + 1;
+            parser.skip_ws()?;
+        }
+        let root = parser.value()?;
+        parser.skip_ws()?;
+        if !parser.rest().is_empty() {
+            return Err(parser.error("expected the end of the file"));
+        }
+        Ok(RonDocument { extensions, root })
+    }
+}
+
+/// Applies each patch in order to a RON asset
+pub fn patch_ron(base: &[u8], patches: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let parse = |bytes: &[u8]| std::str::from_utf8(bytes)
+        .map_err(|e| e.to_string())
+        .and_then(|s| s.parse::<RonDocument>());
+    let mut doc = parse(base)?;
+    for patch in patches {
+        doc.root.apply(parse(patch)?.root)?;
+    }
+    Ok(doc.to_string().into_bytes())
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn error(&self, message: &str) -> String {
+        let line = self.src[..self.pos].matches('\n').count() + 1;
+        format!("RON patch error at line {}: {}", line, message)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_ws()?;
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c)))
+        }
+    }
+
+    fn skip_ws(&mut self) -> Result<(), String> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                // block comments nest in RON
+                let mut depth = 0;
+                let mut i = 0;
+                let bytes = trimmed.as_bytes();
+                loop {
+                    if i + 1 >= bytes.len() {
+                        return Err(self.error("unclosed block comment"));
+                    }
+                    match &bytes[i..i + 2] {
+                        b"/*" => { depth += 1; i += 2; },
+                        b"*/" => {
+                            depth -= 1;
+                            i += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        },
+                        _ => { i += 1; },
+                    }
+                }
+                self.pos += i;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        let rest = self.rest();
+        let start = if rest.starts_with("r#") { 2 } else { 0 };
+        let len = rest[start..].find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len() - start);
+        match rest[start..].chars().next() {
+            Some(c) if len > 0 && (c.is_alphabetic() || c == '_') => {
+                self.pos += start + len;
+                Some(&rest[..start + len])
+            },
+            _ => None,
+        }
+    }
+
+    /// Scans a quoted string or char, escapes included, and returns it as written
+    fn quoted(&mut self, quote: char) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            if c == '\\' {
+                chars.next();
+            } else if c == quote {
+                self.pos += i + 1;
+                return Ok(&rest[..i + 1]);
+            }
+        }
+        Err(self.error("unclosed string"))
+    }
+
+    fn raw_string(&mut self) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let hashes = rest[1..].chars().take_while(|c| *c == '#').count();
+        let closing = format!("\"{}", "#".repeat(hashes));
+        let body = 1 + hashes + 1;
+        match rest.get(body..).and_then(|s| s.find(&closing)) {
+            Some(end) => {
+                let len = body + end + closing.len();
+                self.pos += len;
+                Ok(&rest[..len])
+            },
+            None => Err(self.error("unclosed raw string")),
+        }
+    }
+
+    fn value(&mut self) -> Result<RonNode, String> {
+        self.skip_ws()?;
+        let rest = self.rest();
+        match self.peek() {
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_ws()?;
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        return Ok(RonNode::List(items));
+                    }
+                    items.push(self.value()?);
+                    self.comma_or(']')?;
+                }
+            },
+            Some('{') => {
+                self.pos += 1;
+                let mut entries = Vec::new();
+                loop {
+                    self.skip_ws()?;
+                    if self.peek() == Some('}') {
+                        self.pos += 1;
+                        return Ok(RonNode::Map(entries));
+                    }
+                    let key = self.value()?;
+                    self.expect(':')?;
+                    entries.push((key, self.value()?));
+                    self.comma_or('}')?;
+                }
+            },
+            Some('(') => self.parens(None),
+            Some('"') => Ok(RonNode::Atom(self.quoted('"')?.to_string())),
+            Some('\'') => Ok(RonNode::Atom(self.quoted('\'')?.to_string())),
+            Some('r') if rest.starts_with("r\"") || rest.starts_with("r#\"") || rest.starts_with("r##") => {
+                Ok(RonNode::Atom(self.raw_string()?.to_string()))
+            },
+            Some('b') if rest.starts_with("b\"") || rest.starts_with("b'") => {
+                self.pos += 1;
+                let quote = self.peek().unwrap();
+                Ok(RonNode::Atom(format!("b{}", self.quoted(quote)?)))
+            },
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => {
+                let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == '+' || c == '-')).unwrap_or(rest.len());
+                self.pos += len;
+                Ok(RonNode::Atom(rest[..len].to_string()))
+            },
+            _ => match self.ident() {
+                Some(name) => {
+                    let name = name.to_string();
+                    let before = self.pos;
+                    self.skip_ws()?;
+                    if self.peek() == Some('(') {
+                        self.parens(Some(name))
+                    } else {
+                        self.pos = before;
+                        Ok(RonNode::Atom(name))
+                    }
+                },
+                None => Err(self.error("expected a value")),
+            },
+        }
+    }
+
+    fn comma_or(&mut self, close: char) -> Result<(), String> {
+        self.skip_ws()?;
+        match self.peek() {
+            Some(',') => { self.pos += 1; Ok(()) },
+            Some(c) if c == close => Ok(()),
+            _ => Err(self.error(&format!("expected `,` or `{}`", close))),
+        }
+    }
+
+    /// Parses what's inside `name(...)`, which is a struct if it starts with `field:`
+    fn parens(&mut self, name: Option<String>) -> Result<RonNode, String> {
+        self.expect('(')?;
+        self.skip_ws()?;
+        let start = self.pos;
+        let is_struct = self.ident().is_some() && {
+            self.skip_ws()?;
+            self.rest().starts_with(':') && !self.rest().starts_with("::")
+        };
+        self.pos = start;
+
+        if is_struct {
+            let mut fields = Vec::new();
+            loop {
+                self.skip_ws()?;
+                if self.peek() == Some(')') {
+                    self.pos += 1;
+                    return Ok(RonNode::Struct { name, fields });
+                }
+                let field = self.ident().ok_or_else(|| self.error("expected a field name"))?.to_string();
+                self.expect(':')?;
+                fields.push((field, self.value()?));
+                self.comma_or(')')?;
+            }
+        } else {
+            let mut items = Vec::new();
+            loop {
+                self.skip_ws()?;
+                if self.peek() == Some(')') {
+                    self.pos += 1;
+                    return Ok(RonNode::Tuple { name, items });
+                }
+                items.push(self.value()?);
+                self.comma_or(')')?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, thread, time::Duration};
+
+    use crate::{data::{assetio::{VirtualFileOverrides, super_path}, module::LoadEntry, palette::Palette}, util::ron_options};
+
+    use super::{RonDocument, patch_ron, patch_target};
+
+    fn patched(base: &str, patch: &str) -> String {
+        String::from_utf8(patch_ron(base.as_bytes(), &[patch.as_bytes().to_vec()]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn ron_round_trip() {
+        let src = r##"#![enable(implicit_some)]
+            // a comment, with ( unbalanced ] things
+            (
+                name: "a \"quoted\" (string)",
+                raw: r#"raw " string"#,
+                chr: ')',
+                /* nested /* block */ comment */
+                nums: [1, -2.5, 1e-3, 0x1f,],
+                shape: Quad(w: 0.771, h: 1.3),
+                unit: None,
+                some: Some((1., 2.)),
+                map: { "k": Sprite(bone: (name: "root")), },
+                empty: (),
+            )"##;
+        let doc: RonDocument = src.parse().unwrap();
+        assert_eq!(
+            "#![enable(implicit_some)]\n(name: \"a \\\"quoted\\\" (string)\", raw: r#\"raw \" string\"#, chr: ')', nums: [1, -2.5, 1e-3, 0x1f, ], shape: Quad(w: 0.771, h: 1.3, ), unit: None, some: Some((1., 2., ), ), map: {\"k\": Sprite(bone: (name: \"root\", ), ), }, empty: (), )",
+            doc.to_string()
+        );
+        assert_eq!(doc, doc.to_string().parse().unwrap());
+    }
+
+    #[test]
+    fn ron_patch_merge() {
+        assert_eq!(
+            "(colors: {\"black\": \"#000000\", \"red\": \"#a54545\", \"neon\": \"#39ff14\", }, background: \"black\", )",
+            patched(
+                r##"(colors: { "black": "#1c1a23", "red": "#a54545" }, background: "black")"##,
+                r##"(colors: { "black": "#000000", "neon": "#39ff14" })"##,
+            ),
+        );
+        assert_eq!(
+            "(startup_scripts: [\"a.lua\", \"b.lua\", ], priority: 5., )",
+            patched(r#"(startup_scripts: ["a.lua"], priority: 1.)"#, r#"(startup_scripts: ["b.lua"], priority: 5.)"#),
+        );
+        assert_eq!(
+            "(shape: Quad(w: 2., h: 1.3, ), )",
+            patched("(shape: Quad(w: 0.771, h: 1.3))", "(shape: (w: 2.))"),
+            "unnamed patches merge into named structs"
+        );
+        assert_eq!(
+            "(shape: Circle(r: 1., ), )",
+            patched("(shape: Quad(w: 0.771, h: 1.3))", "(shape: Circle(r: 1.))"),
+            "a different variant replaces the old one"
+        );
+    }
+
+    #[test]
+    fn ron_patch_directives() {
+        assert_eq!(
+            "(startup_scripts: [\"b.lua\", ], )",
+            patched(r#"(startup_scripts: ["a.lua"])"#, r#"(startup_scripts: Replace(["b.lua"]))"#),
+        );
+        assert_eq!(
+            "(colors: {\"red\": \"#a54545\", }, )",
+            patched(r##"(colors: { "black": "#1c1a23", "red": "#a54545" }, background: "black")"##, r#"(colors: { "black": Remove }, background: Remove)"#),
+        );
+        assert!(patch_ron(b"[1, 2]", &[b"[Remove]".to_vec()]).is_err());
+    }
+
+    #[test]
+    fn ron_patch_palette() {
+        let base = std::fs::read("assets/palettes/default.palette.ron").unwrap();
+        let patch = br##"(colors: { "black": "#000000", "neon": "#39ff14" })"##.to_vec();
+        let bytes = patch_ron(&base, &[patch]).unwrap();
+        let palette: Palette = ron_options().from_bytes(&bytes).unwrap();
+        assert!(palette.colors.contains_key("neon"));
+        assert!(palette.colors.contains_key("red"));
+
+        assert_eq!(Some("palettes/default.palette.ron"), patch_target("palettes/default.palette.ron.patch"));
+        assert_eq!(None, patch_target("notes.txt.patch"));
+    }
+
+    #[test]
+    fn ron_patch_overrides() {
+        let root = std::env::temp_dir().join(format!("bifrons_ron_patch_{}", std::process::id()));
+        fs::create_dir_all(root.join("base_textures/textures")).unwrap();
+        fs::create_dir_all(root.join("texture_patch/textures")).unwrap();
+        fs::write(root.join("base_textures/textures/wall.ron"), "(size: 1, tags: [\"stone\"])").unwrap();
+        fs::write(root.join("texture_patch/textures/wall.ron.patch"), "(tags: [\"mossy\"])").unwrap();
+
+        let load_order = vec![
+            vec![LoadEntry { name: "base_textures".to_string(), root: "base_textures".to_string() }],
+            vec![LoadEntry { name: "texture_patch".to_string(), root: "texture_patch".to_string() }],
+        ];
+        let mut vfo = VirtualFileOverrides { overrides: Default::default(), zipped_dirs: Default::default(), lua_path: String::new(), providers: Default::default() };
+        vfo.populate_files_in(&root, &load_order);
+        let overrides = vfo.overrides.read();
+        assert_eq!(
+            "(size: 1, tags: [\"stone\", \"mossy\", ], )",
+            String::from_utf8(overrides[Path::new("textures/wall.ron")].read().unwrap()).unwrap(),
+            "texture_patch's patch should apply on top of base_textures' version"
+        );
+        let base = overrides[&super_path("texture_patch", Path::new("textures/wall.ron"))].source_path();
+        assert_eq!(root.join("base_textures/textures/wall.ron"), base);
+        assert!(!overrides.contains_key(Path::new("textures/wall.ron.patch")));
+        let wall = overrides[Path::new("textures/wall.ron")].clone();
+        drop(overrides);
+
+        // editing only the patch still counts as the patched file changing
+        assert_eq!(
+            vec![root.join("base_textures/textures/wall.ron"), root.join("texture_patch/textures/wall.ron.patch")],
+            wall.source_paths(),
+        );
+        let before = wall.modified().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        fs::write(root.join("texture_patch/textures/wall.ron.patch"), "(tags: [\"cracked\"])").unwrap();
+        assert!(wall.modified().unwrap() > before);
+
+        assert_eq!("textures/wall.ron: base_textures < texture_patch (using texture_patch)", vfo.conflict_report());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
 use mlua::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::scripting::{LuaMod};
+use crate::{scripting::{LuaMod, permission::ScriptPermissions}, system::module::LoadedModList};
+
+use super::{lua::{LuaWorld, ScriptVar}, lang::ENGLISH, module::Module};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Setting {
@@ -22,3 +27,106 @@ impl LuaUserData for Setting {
         methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| Ok(format!("{:?}", this)));
     }
 }
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum SettingKind {
+    Bool,
+    Int,
+    Num,
+    Str,
+}
+
+/// A tunable declared in a `.mod.ron`, which players can change through the `settings` of that mod's modlist entry
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SettingDef {
+    pub kind:    SettingKind,
+    pub default: ScriptVar,
+    #[serde(default)]
+    pub min:     Option<f64>,
+    #[serde(default)]
+    pub max:     Option<f64>,
+    /// Key into the mod's `lines` for this setting's label, or `setting.<name>` if not given
+    #[serde(default)]
+    pub label:   Option<String>,
+}
+impl SettingDef {
+    /// Returns the value if it fits this setting's kind and range, converting Ints into Nums as needed
+    pub fn validate(&self, value: &ScriptVar) -> Result<ScriptVar, String> {
+        let value = match (self.kind, value) {
+            (SettingKind::Bool, ScriptVar::Bool(_)) => value.clone(),
+            (SettingKind::Int,  ScriptVar::Int(_))  => value.clone(),
+            (SettingKind::Num,  ScriptVar::Num(_))  => value.clone(),
+            (SettingKind::Num,  ScriptVar::Int(i))  => ScriptVar::Num(*i as f64),
+            (SettingKind::Str,  ScriptVar::Str(_))  => value.clone(),
+            (kind, value) => return Err(format!("expected a {:?}, but found {:?}", kind, value)),
+        };
+        let n = match value {
+            ScriptVar::Int(i) => Some(i as f64),
+            ScriptVar::Num(n) => Some(n),
+            _ => None,
+        };
+        if let Some(n) = n {
+            if let Some(min) = self.min && n < min {
+                return Err(format!("{} is less than the minimum of {}", n, min));
+            }
+            if let Some(max) = self.max && n > max {
+                return Err(format!("{} is greater than the maximum of {}", n, max));
+            }
+        }
+        Ok(value)
+    }
+
+    pub fn label_key(&self, name: &str) -> String {
+        self.label.clone().unwrap_or_else(|| format!("setting.{}", name))
+    }
+}
+
+/// The `ModSettings` Lua module, giving scripts the resolved settings of the mod that owns them
+pub struct ModSettings;
+impl ModSettings {
+    fn owner(lua: &Lua) -> Result<String, LuaError> {
+        lua.app_data_ref::<ScriptPermissions>()
+            .map(|p| p.owner.clone())
+            .ok_or_else(|| LuaError::RuntimeError("ModSettings can only be used by scripts that belong to a mod".to_string()))
+    }
+
+    fn with_settings<F, R>(lua: &Lua, f: F) -> Result<R, LuaError> where F: FnOnce(&String, &HashMap<String, ScriptVar>) -> Result<R, LuaError> {
+        let owner = ModSettings::owner(lua)?;
+        let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+        let w = world.read();
+        let loaded_ml = w.resource::<LoadedModList>();
+        match loaded_ml.settings.get(&owner) {
+            Some(settings) => f(&owner, settings),
+            None           => f(&owner, &HashMap::new()),
+        }
+    }
+}
+impl LuaMod for ModSettings {
+    fn mod_name() -> &'static str { "ModSettings" }
+
+    fn register_defs(lua: &Lua, table: &mut LuaTable) -> Result<(), mlua::Error> {
+        table.set("all", lua.create_function(|lua, ()| {
+            let settings = ModSettings::with_settings(lua, |_, settings| Ok(settings.clone()))?;
+            settings.to_lua(lua)
+        })?)?;
+        table.set("get", lua.create_function(|lua, name: String| {
+            let value = ModSettings::with_settings(lua, |owner, settings| {
+                settings.get(&name).cloned()
+                    .ok_or_else(|| LuaError::RuntimeError(format!("Mod `{}` has no setting named `{}`", owner, name)))
+            })?;
+            value.to_lua(lua)
+        })?)?;
+        table.set("label", lua.create_function(|lua, (name, lang): (String, Option<String>)| {
+            let owner = ModSettings::owner(lua)?;
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let w = world.read();
+            let loaded_ml = w.resource::<LoadedModList>();
+            let module = loaded_ml.handles.get(&owner).and_then(|h| w.resource::<Assets<Module>>().get(h));
+            Ok(module.and_then(|m| {
+                let key = m.settings.get(&name)?.label_key(&name);
+                m.lines.get(lang.as_deref().unwrap_or(ENGLISH))?.get(&key).cloned()
+            }))
+        })?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,12 @@
+#![feature(default_free_fn)]
+#![feature(float_next_up_down)]
+#![feature(hash_drain_filter)]
+#![feature(hash_raw_entry)]
+#![feature(io_error_other)]
+#![feature(iter_intersperse)]
+#![feature(let_chains)]
+
+pub mod data;
+pub mod scripting;
+pub mod system;
+pub mod util;
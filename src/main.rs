@@ -1,30 +1,18 @@
-#![feature(default_free_fn)]
-#![feature(float_next_up_down)]
-#![feature(hash_drain_filter)]
-#![feature(hash_raw_entry)]
-#![feature(io_error_other)]
-#![feature(iter_intersperse)]
-#![feature(let_chains)]
-
 use bevy_inspector_egui::WorldInspectorPlugin;
 use bevy_kira_audio::prelude::*;
 use bevy::{prelude::*, diagnostic::{LogDiagnosticsPlugin}};
 use bevy_rapier3d::{prelude::{NoUserData, RapierPhysicsPlugin}};
-use data::{prefab::{Prefab, PrefabLoader}, formlist::{FormList, FormListLoader}, assetio::{VirtualAssetIo, VirtualFileOverrides}};
+use bifrons::{data::{prefab::{Prefab, PrefabLoader}, formlist::{FormList, FormListLoader}, assetio::{VirtualAssetIo, VirtualFileOverrides}}, scripting, system};
 use scripting::ui::font::{FontLoader, UIFont};
 
-mod data;
-mod scripting;
-mod system;
-mod util;
-
 fn main() {
     let asset_io = VirtualAssetIo::new();
     let overrides = asset_io.overrides();
+    let zipped_dirs = asset_io.zipped_dirs();
 
     App::new()
         .insert_resource(AssetServer::new(asset_io))
-        .insert_resource(VirtualFileOverrides { overrides, lua_path: String::new() })
+        .insert_resource(VirtualFileOverrides { overrides, zipped_dirs, lua_path: String::new(), providers: default() })
         .add_plugins(DefaultPlugins)
         // debug
         .add_plugin(LogDiagnosticsPlugin::default())
@@ -37,6 +25,7 @@ fn main() {
         .add_plugin(system::action::ActionPlugin)
         .add_plugin(system::anim::AnimPlugin)
         .add_plugin(system::camera::CameraPlugin)
+        .add_plugin(system::diagnostics::ScriptDiagnosticsPlugin)
         .add_plugin(system::lua::LuaPlugin)
         .add_plugin(system::level::LevelPlugin)
         .add_plugin(system::module::ModulePlugin)
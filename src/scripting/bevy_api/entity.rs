@@ -1,15 +1,31 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use bevy::{prelude::*};
+use bevy::{prelude::*, reflect::{serde::{TypedReflectDeserializer, TypedReflectSerializer}, Reflect, TypeRegistration, TypeRegistry}};
 use mlua::prelude::*;
+use serde::de::DeserializeSeed;
 
-use crate::{data::{lua::LuaWorld, prefab::Tags}, scripting::LuaMod};
+use crate::{data::{lua::LuaWorld, prefab::Tags}, scripting::{LuaMod, permission::{self, ScriptPermissions}, query::type_registration}, system::lua::{DetachScripts, ScriptRefs, SharedInstances}};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct LuaEntity(pub Entity);
 impl LuaEntity {
     pub fn new(entity: Entity) -> Self { LuaEntity(entity) }
 }
+impl LuaEntity {
+    fn component<'r>(registry: &'r TypeRegistry, type_name: &str) -> Result<(&'r TypeRegistration, &'r ReflectComponent), LuaError> {
+        let registration = type_registration(registry, type_name)?;
+        let component = registration.data::<ReflectComponent>()
+            .ok_or_else(|| LuaError::RuntimeError(format!("Type {} isn't a reflected component", type_name)))?;
+        Ok((registration, component))
+    }
+
+    /// Builds a reflected value of a component's type from a Lua table, which only has the fields it sets
+    fn patch(registration: &TypeRegistration, registry: &TypeRegistry, value: LuaValue) -> Result<Box<dyn Reflect>, LuaError> {
+        TypedReflectDeserializer::new(registration, registry)
+            .deserialize(mlua::serde::Deserializer::new(value))
+            .map_err(|e| LuaError::RuntimeError(format!("Unable to make a {} from the table: {}", registration.short_name(), e)))
+    }
+}
 impl LuaUserData for LuaEntity {
     fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
         fields.add_field_method_get("index", |_, this| Ok(this.0.index()));
@@ -33,6 +49,75 @@ impl LuaUserData for LuaEntity {
             ent.despawn();
             Ok(())
         });
+        methods.add_method("detach_script", |lua, this, path: String| {
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let mut w = world.write();
+            let detached = {
+                let mut si = w.resource_mut::<SharedInstances>();
+                if !si.by_path.get(&path).map_or(false, |entities| entities.contains_key(&this.0)) {
+                    return Ok(false);
+                }
+                si.detach(this.0, &path)
+            };
+            if let Some(id) = detached {
+                if let Some(mut script_refs) = w.get_mut::<ScriptRefs>(this.0) {
+                    script_refs.ids.remove(&id);
+                }
+                w.resource_mut::<Events<DetachScripts>>().send(DetachScripts { entity: this.0, ids: HashSet::from([id]) });
+            }
+            Ok(true)
+        });
+        methods.add_method("get", |lua, this, type_name: String| {
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let w = world.read();
+            let registry = w.resource::<AppTypeRegistry>().read();
+            let (_, component) = LuaEntity::component(&registry, &type_name)?;
+            match component.reflect(&w, this.0) {
+                Some(value) => lua.to_value(&TypedReflectSerializer::new(value, &registry)).map(Some),
+                None        => Ok(None),
+            }
+        });
+        methods.add_method("set", |lua, this, (type_name, value): (String, LuaValue)| {
+            ScriptPermissions::check(lua, "entity:set", permission::WORLD_WRITE)?;
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let mut w = world.write();
+            let registry = w.resource::<AppTypeRegistry>().clone();
+            let registry = registry.read();
+            let (registration, component) = LuaEntity::component(&registry, &type_name)?;
+            if component.reflect(&w, this.0).is_none() {
+                return Err(LuaError::RuntimeError(format!("{:?} has no {} to set, which insert can add", this.0, type_name)));
+            }
+            let patch = LuaEntity::patch(registration, &registry, value)?;
+            component.apply(&mut w, this.0, &*patch);
+            Ok(())
+        });
+        methods.add_method("insert", |lua, this, (type_name, value): (String, Option<LuaValue>)| {
+            ScriptPermissions::check(lua, "entity:insert", permission::WORLD_WRITE)?;
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let mut w = world.write();
+            if w.get_entity(this.0).is_none() {
+                return Err(LuaError::RuntimeError(format!("{:?} doesn't exist", this.0)));
+            }
+            let registry = w.resource::<AppTypeRegistry>().clone();
+            let registry = registry.read();
+            let (registration, component) = LuaEntity::component(&registry, &type_name)?;
+            let patch = LuaEntity::patch(registration, &registry, value.unwrap_or(LuaValue::Table(lua.create_table()?)))?;
+            component.apply_or_insert(&mut w, this.0, &*patch);
+            Ok(())
+        });
+        methods.add_method("remove", |lua, this, type_name: String| {
+            ScriptPermissions::check(lua, "entity:remove", permission::WORLD_WRITE)?;
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let mut w = world.write();
+            let registry = w.resource::<AppTypeRegistry>().clone();
+            let registry = registry.read();
+            let (_, component) = LuaEntity::component(&registry, &type_name)?;
+            if component.reflect(&w, this.0).is_none() {
+                return Ok(false);
+            }
+            component.remove(&mut w, this.0);
+            Ok(true)
+        });
         methods.add_method("hide", |lua, this, ()| {
             if let Some(mut ent_mut) = lua.globals().get::<_, LuaWorld>("world").unwrap().write().get_entity_mut(this.0) {
                 ent_mut.insert(Visibility { is_visible: false });
@@ -71,4 +156,78 @@ impl From<Entity> for LuaEntity {
 }
 impl From<LuaEntity> for Entity {
     fn from(LuaEntity(e): LuaEntity) -> Self { e }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::system::camera::{ActiveCamera, Focus};
+
+    use super::*;
+
+    fn world() -> World {
+        let registry = AppTypeRegistry::default();
+        {
+            let mut registry = registry.write();
+            registry.register::<Transform>();
+            registry.register::<Vec3>();
+            registry.register::<Quat>();
+            registry.register::<ActiveCamera>();
+            registry.register::<Focus>();
+            registry.register::<Entity>();
+            registry.register::<Option<Entity>>();
+        }
+        let mut world = World::new();
+        world.insert_resource(registry);
+        world
+    }
+
+    fn lua(world: &mut World, granted: &[&str]) -> Lua {
+        let lua = Lua::new();
+        lua.globals().set("world", unsafe { LuaWorld::new(world) }).unwrap();
+        lua.set_app_data(ScriptPermissions::new("test".to_string(), granted.iter().map(|p| p.to_string()).collect()));
+        lua
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut world = world();
+        let target = world.spawn_empty().id();
+        let from = world.spawn((
+            Transform::from_xyz(1., 2., 3.).with_scale(Vec3::splat(2.)),
+            ActiveCamera { controller: Some(target), focus: Focus::Entity { which: target, offset: Vec3::Y } },
+        )).id();
+        let to = world.spawn((Transform::default(), ActiveCamera::default())).id();
+        let blank = world.spawn(ActiveCamera::default()).id();
+
+        let lua = lua(&mut world, &[permission::WORLD_WRITE]);
+        let copy = lua.load(r#"
+            local from, to = ...
+            for _, name in ipairs({ "Transform", "ActiveCamera" }) do
+                to:set(name, from:get(name))
+            end
+        "#).into_function().unwrap();
+        copy.call::<_, ()>((LuaEntity(from), LuaEntity(to))).unwrap();
+        assert_eq!(world.get::<Transform>(to), world.get::<Transform>(from));
+        assert_eq!(world.get::<ActiveCamera>(to), world.get::<ActiveCamera>(from));
+
+        // a camera without a controller clears it
+        lua.load("select(2, ...):set('ActiveCamera', (...):get('ActiveCamera'))")
+            .call::<_, ()>((LuaEntity(blank), LuaEntity(to)))
+            .unwrap();
+        assert_eq!(world.get::<ActiveCamera>(to), Some(&ActiveCamera::default()));
+    }
+
+    #[test]
+    fn writes_need_permission() {
+        let mut world = world();
+        let entity = world.spawn(Transform::from_xyz(1., 2., 3.)).id();
+        let lua = lua(&mut world, &[]);
+        lua.globals().set("e", LuaEntity(entity)).unwrap();
+
+        assert!(lua.load("return e:get('Transform')").eval::<Option<LuaTable>>().unwrap().is_some());
+        for write in ["e:set('Transform', {})", "e:insert('ActiveCamera')", "e:remove('Transform')"] {
+            let err = lua.load(write).exec().unwrap_err().to_string();
+            assert!(err.contains(permission::WORLD_WRITE), "{}: {}", write, err);
+        }
+        assert!(world.get::<Transform>(entity).is_some());
+    }
+}
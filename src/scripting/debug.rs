@@ -0,0 +1,37 @@
+use mlua::prelude::*;
+
+use super::{LuaMod, bevy_api::LuaEntity, diagnostics::{ProfileKey, ProfileStats, ScriptProfiler}};
+
+#[derive(Default)]
+pub struct DebugAPI;
+impl LuaMod for DebugAPI {
+    fn mod_name() -> &'static str { "Debug" }
+    fn register_defs(lua: &Lua, table: &mut LuaTable) -> Result<(), mlua::Error> {
+        table.set("profile", lua.create_function(|lua, script: Option<String>| {
+            let mut stats: Vec<(ProfileKey, ProfileStats)> = match lua.app_data_ref::<ScriptProfiler>() {
+                Some(profiler) => profiler.stats().iter()
+                    .filter(|(key, _)| script.as_ref().map_or(true, |s| *s == key.script))
+                    .map(|(key, stats)| (key.clone(), *stats))
+                    .collect(),
+                None => Vec::new(),
+            };
+            stats.sort_by(|a, b| b.1.total.cmp(&a.1.total));
+            let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.;
+            let profile = lua.create_table()?;
+            for (key, stats) in stats {
+                let entry = lua.create_table()?;
+                entry.set("script",     key.script)?;
+                entry.set("hook",       key.hook)?;
+                entry.set("entity",     key.entity.map(LuaEntity))?;
+                entry.set("calls",      stats.calls)?;
+                entry.set("total",      ms(stats.total))?;
+                entry.set("average",    ms(stats.average()))?;
+                entry.set("max",        ms(stats.max))?;
+                entry.set("last_frame", ms(stats.last_frame))?;
+                profile.push(entry)?;
+            }
+            Ok(profile)
+        })?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use bevy::prelude::*;
+use mlua::prelude::*;
+use parking_lot::{RwLock, RwLockReadGuard};
+
+use super::permission::ScriptPermissions;
+
+/// The path of the script an instance was loaded from, which is what its errors are recorded under
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScriptPath(pub String);
+
+/// The mod owning each script run in a scope shared by several of them, like the collectivist's, by script path
+///
+/// Errors and time spent in such a scope are recorded under the script that threw or defined the function that ran,
+/// rather than the scope's own [`ScriptPath`]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SharedScriptOwners(pub HashMap<String, String>);
+impl SharedScriptOwners {
+    /// The script this path names and its owner, where long paths Lua shortened to `...` and their end still match
+    fn find(&self, path: &str) -> Option<(String, String)> {
+        match path.strip_prefix("...") {
+            Some(end) => self.0.iter().find(|(script, _)| script.ends_with(end)),
+            None      => self.0.get_key_value(path),
+        }.map(|(script, owner)| (script.clone(), owner.clone()))
+    }
+}
+
+/// The script and mod that code from `path` belongs to, which is the instance's own unless its scope is shared
+pub fn script_of(lua: &Lua, path: Option<&str>) -> (String, String) {
+    let shared = path.and_then(|path| lua.app_data_ref::<SharedScriptOwners>().and_then(|owners| owners.find(path)));
+    shared.unwrap_or_else(|| (
+        lua.app_data_ref::<ScriptPath>().map(|p| p.0.clone()).unwrap_or_default(),
+        lua.app_data_ref::<ScriptPermissions>().map(|p| p.owner.clone()).unwrap_or_default(),
+    ))
+}
+
+/// The path of the script that defined `f`, if the instance's scope is shared by several
+pub fn defined_in(lua: &Lua, f: &LuaFunction) -> Option<String> {
+    lua.app_data_ref::<SharedScriptOwners>()?;
+    let source = f.info().source?;
+    Some(String::from_utf8_lossy(source.as_ref()).trim_start_matches('@').to_string())
+}
+
+/// A distinct error thrown by a script, with how many times it has been thrown
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    /// The script whose instance threw this
+    pub script:    String,
+    /// The mod that owns the script
+    pub owner:     String,
+    /// The hook or callback that was running, like `on_update` or `load`
+    pub hook:      String,
+    /// The entity the script was running for the last time this was thrown
+    pub entity:    Option<Entity>,
+    pub message:   String,
+    pub traceback: Option<String>,
+    /// The file and line that threw this, which is a required file rather than the script itself if it was thrown there
+    pub location:  Option<(String, usize)>,
+    pub count:     u32,
+}
+
+/// Every error thrown by scripts since the game started
+///
+/// Each instance shares this, so errors can be recorded wherever they're caught, even from inside Lua
+#[derive(Clone, Debug, Default, Resource)]
+pub struct ScriptDiagnostics {
+    errors: Arc<RwLock<Vec<ScriptError>>>,
+}
+impl ScriptDiagnostics {
+    /// Logs an error, and records it or counts it again if the same one was already thrown by this script and hook
+    pub fn record(&self, script: &str, owner: &str, hook: &str, entity: Option<Entity>, err: &LuaError) {
+        let (message, traceback) = split_traceback(err);
+        error!("{} {} threw {}", script, hook, message);
+        let location = error_location(err);
+        let mut errors = self.errors.write();
+        if let Some(known) = errors.iter_mut().find(|e| e.script == script && e.hook == hook && e.message == message) {
+            known.count += 1;
+            known.entity = entity;
+            known.traceback = traceback;
+        } else {
+            errors.push(ScriptError {
+                script: script.to_string(),
+                owner:  owner.to_string(),
+                hook:   hook.to_string(),
+                entity, message, traceback, location,
+                count:  1,
+            });
+        }
+    }
+
+    pub fn errors(&self) -> RwLockReadGuard<Vec<ScriptError>> {
+        self.errors.read()
+    }
+
+    pub fn clear(&self) {
+        self.errors.write().clear();
+    }
+}
+
+/// Records an error caught while running an instance, under the script and mod it was loaded for
+///
+/// In a shared scope that's the script the error was thrown from, if it was one of the scope's own
+pub fn report_error(lua: &Lua, hook: &str, entity: Option<Entity>, err: &LuaError) {
+    let location = error_location(err);
+    let (script, owner) = script_of(lua, location.as_ref().map(|(file, _)| file.as_str()));
+    match lua.app_data_ref::<ScriptDiagnostics>() {
+        Some(diagnostics) => diagnostics.record(&script, &owner, hook, entity, err),
+        None => error!("{} {} threw {}", script, hook, err),
+    }
+}
+
+/// What a script's time is measured under
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ProfileKey {
+    pub script: String,
+    /// The hook or callback that was running, like `on_update` or `timer`
+    pub hook:   String,
+    pub entity: Option<Entity>,
+}
+
+/// How long a script has spent in a hook for an entity
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProfileStats {
+    pub calls:      u64,
+    pub total:      Duration,
+    /// The longest single call
+    pub max:        Duration,
+    /// Time spent during the frame that's still running
+    pub frame:      Duration,
+    /// Time spent during the last frame that finished
+    pub last_frame: Duration,
+}
+impl ProfileStats {
+    pub fn average(&self) -> Duration {
+        if self.calls == 0 { Duration::ZERO } else { self.total.div_f64(self.calls as f64) }
+    }
+}
+
+/// Time spent in every script since the game started or the profiler was reset
+///
+/// Each instance shares this like [`ScriptDiagnostics`], so calls are measured wherever the game makes them
+#[derive(Clone, Debug, Default, Resource)]
+pub struct ScriptProfiler {
+    stats: Arc<RwLock<HashMap<ProfileKey, ProfileStats>>>,
+}
+impl ScriptProfiler {
+    pub fn record(&self, key: ProfileKey, time: Duration) {
+        let mut stats = self.stats.write();
+        let stats = stats.entry(key).or_default();
+        stats.calls += 1;
+        stats.total += time;
+        stats.frame += time;
+        stats.max = stats.max.max(time);
+    }
+
+    pub fn stats(&self) -> RwLockReadGuard<HashMap<ProfileKey, ProfileStats>> {
+        self.stats.read()
+    }
+
+    /// Moves the time spent this frame to `last_frame`, returning how much each script spent in total
+    pub fn end_frame(&self) -> HashMap<String, Duration> {
+        let mut per_script = HashMap::new();
+        for (key, stats) in self.stats.write().iter_mut() {
+            *per_script.entry(key.script.clone()).or_default() += stats.frame;
+            stats.last_frame = std::mem::take(&mut stats.frame);
+        }
+        per_script
+    }
+
+    /// Drops the stats of calls made for an entity that no longer runs any scripts
+    pub fn forget_entity(&self, entity: Entity) {
+        self.stats.write().retain(|key, _| key.entity != Some(entity));
+    }
+
+    pub fn reset(&self) {
+        self.stats.write().clear();
+    }
+}
+
+/// Runs `f`, which calls into the instance, and measures it under the instance's script path
+pub fn profiled<R>(lua: &Lua, hook: &str, entity: Option<Entity>, f: impl FnOnce() -> R) -> R {
+    profiled_in(lua, None, hook, entity, f)
+}
+
+/// Like [`profiled`], for `f` calling `function`, which in a shared scope is measured under the script that defined it
+pub fn profiled_call<R>(lua: &Lua, function: &LuaFunction, hook: &str, entity: Option<Entity>, f: impl FnOnce() -> R) -> R {
+    profiled_in(lua, defined_in(lua, function).as_deref(), hook, entity, f)
+}
+
+/// Like [`profiled`], measuring `f` under the script at `path` if the instance's scope is shared
+pub fn profiled_in<R>(lua: &Lua, path: Option<&str>, hook: &str, entity: Option<Entity>, f: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let result = f();
+    if let Some(profiler) = lua.app_data_ref::<ScriptProfiler>() {
+        let (script, _) = script_of(lua, path);
+        profiler.record(ProfileKey { script, hook: hook.to_string(), entity }, start.elapsed());
+    }
+    result
+}
+
+/// The file and line an error was thrown from, from its message or otherwise its traceback
+pub fn error_location(err: &LuaError) -> Option<(String, usize)> {
+    let (message, traceback) = split_traceback(err);
+    parse_location(&message).or_else(|| traceback.as_deref().and_then(parse_location))
+}
+
+/// Separates the traceback mlua attaches to errors from their message
+pub fn split_traceback(err: &LuaError) -> (String, Option<String>) {
+    match err {
+        LuaError::CallbackError { traceback, cause } => (split_traceback(cause).0, Some(traceback.clone())),
+        LuaError::SyntaxError { message, .. } => (message.clone(), None),
+        err => {
+            let s = err.to_string();
+            match s.split_once("\nstack traceback:") {
+                Some((message, traceback)) => (message.to_string(), Some(format!("stack traceback:{}", traceback))),
+                None => (s, None),
+            }
+        },
+    }
+}
+
+/// Finds the first `file:line:` in the text, which is how Lua points at where an error was thrown
+pub fn parse_location(text: &str) -> Option<(String, usize)> {
+    text.lines().find_map(|line| {
+        let mut parts = line.trim().splitn(3, ':');
+        let file = parts.next()?;
+        let line = parts.next()?.parse().ok()?;
+        parts.next()?;
+        if file.is_empty() || file.starts_with('[') {
+            None
+        } else {
+            Some((file.to_string(), line))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Entity;
+    use mlua::prelude::*;
+
+    use std::time::Duration;
+
+    use std::collections::HashMap;
+
+    use super::{parse_location, profiled_call, report_error, split_traceback, ProfileKey, ScriptDiagnostics, ScriptPath, ScriptProfiler, SharedScriptOwners};
+
+    #[test]
+    fn error_location() {
+        assert_eq!(Some(("editor/main.lua".to_string(), 12)), parse_location("editor/main.lua:12: attempt to call a nil value"));
+        assert_eq!(
+            Some(("core/util.lua".to_string(), 3)),
+            parse_location("stack traceback:\n\t[C]: in ?\n\tcore/util.lua:3: in function 'explode'\n\tcore/main.lua:9: in function 'on_init'"),
+        );
+        assert_eq!(None, parse_location("[string \"--!shared\"]:4: oops"));
+        assert_eq!(None, parse_location("not enough memory"));
+    }
+
+    #[test]
+    fn error_traceback() {
+        let lua = Lua::new();
+        let err = lua.load("local x = nil\nx()").set_name("@mod/main.lua").unwrap().exec().unwrap_err();
+        let (message, _) = split_traceback(&err);
+        assert!(message.starts_with("mod/main.lua:2:"), "{}", message);
+        assert_eq!(Some(("mod/main.lua".to_string(), 2)), parse_location(&message));
+    }
+
+    #[test]
+    fn profile_frames() {
+        let profiler = ScriptProfiler::default();
+        let key = |hook: &str| ProfileKey { script: "mod/main.lua".to_string(), hook: hook.to_string(), entity: None };
+        profiler.record(key("on_update"), Duration::from_millis(2));
+        profiler.record(key("on_update"), Duration::from_millis(4));
+        profiler.record(key("timer"), Duration::from_millis(1));
+        assert_eq!(Some(&Duration::from_millis(7)), profiler.end_frame().get("mod/main.lua"));
+
+        let stats = profiler.stats()[&key("on_update")];
+        assert_eq!((2, Duration::from_millis(4), Duration::from_millis(3)), (stats.calls, stats.max, stats.average()));
+        assert_eq!((Duration::ZERO, Duration::from_millis(6)), (stats.frame, stats.last_frame));
+        assert_eq!(Some(&Duration::ZERO), profiler.end_frame().get("mod/main.lua"));
+
+        let entity = Entity::from_raw(3);
+        profiler.record(ProfileKey { entity: Some(entity), ..key("on_update") }, Duration::from_millis(1));
+        profiler.forget_entity(entity);
+        assert_eq!(2, profiler.stats().len());
+    }
+
+    #[test]
+    fn shared_scope() {
+        let lua = Lua::new();
+        let (diagnostics, profiler) = (ScriptDiagnostics::default(), ScriptProfiler::default());
+        lua.set_app_data(ScriptPath("collectivist".to_string()));
+        lua.set_app_data(diagnostics.clone());
+        lua.set_app_data(profiler.clone());
+        lua.set_app_data(SharedScriptOwners(HashMap::from([
+            ("first/main.lua".to_string(),  "first".to_string()),
+            ("second/main.lua".to_string(), "second".to_string()),
+        ])));
+        lua.load("function tick() end").set_name("@first/main.lua").unwrap().exec().unwrap();
+        lua.load("function boom() error('boom') end").set_name("@second/main.lua").unwrap().exec().unwrap();
+
+        let tick: LuaFunction = lua.globals().get("tick").unwrap();
+        profiled_call(&lua, &tick, "on_update", None, || tick.call::<_, ()>(())).unwrap();
+        assert!(profiler.stats().contains_key(&ProfileKey { script: "first/main.lua".to_string(), hook: "on_update".to_string(), entity: None }));
+
+        let err = lua.load("boom()").set_name("@third/main.lua").unwrap().exec().unwrap_err();
+        report_error(&lua, "on_update", None, &err);
+        let err = lua.load("error('elsewhere')").set_name("@third/main.lua").unwrap().exec().unwrap_err();
+        report_error(&lua, "on_update", None, &err);
+        let errors = diagnostics.errors();
+        assert_eq!(("second/main.lua", "second"), (errors[0].script.as_str(), errors[0].owner.as_str()));
+        assert_eq!("collectivist", errors[1].script);
+    }
+}
@@ -1,17 +1,122 @@
-use bitflags::bitflags;
+use std::collections::{HashMap, HashSet};
 
-bitflags! {
-    pub struct EventFlag: u64 {
-        // const ON_INIT        = 0b0000; // every script subscribes to this
-        const ON_UPDATE      = 0b0001;
-        const ON_ROOM_REVEAL = 0b0010;
+use bevy::prelude::*;
+use mlua::prelude::*;
+
+use crate::{data::lua::{Hook, LuaWorld, ManyTransVars, TransVar}, system::lua::{EventCall, LuaEventQueue}};
+
+use super::LuaMod;
+
+/// The prefix of every hook, which scripts subscribe to an event with by defining `on_<event name>`
+pub const HOOK_PREFIX: &str = "on_";
+
+/// Events the game sends itself, or that only reach a script through its own entities, so scripts can't emit them
+pub const RESERVED_EVENTS: [&str; 5] = ["drop", "init", "reload", "room_reveal", "update"];
+
+/// Which instances define each hook, and which entities run each instance, so events only reach the scripts that handle them
+#[derive(Clone, Debug, Default)]
+pub struct EventSubscribers {
+    by_hook:  HashMap<String, HashSet<u32>>,
+    hooks:    HashMap<u32, HashSet<String>>,
+    entities: HashMap<u32, HashSet<Entity>>,
+}
+impl EventSubscribers {
+    /// Replaces the hooks an instance is subscribed to
+    pub fn subscribe(&mut self, id: u32, hooks: HashSet<String>) {
+        self.unsubscribe(id);
+        for hook in hooks.iter() {
+            self.by_hook.entry(hook.clone()).or_default().insert(id);
+        }
+        self.hooks.insert(id, hooks);
+    }
+
+    fn unsubscribe(&mut self, id: u32) {
+        for hook in self.hooks.remove(&id).unwrap_or_default() {
+            if let Some(ids) = self.by_hook.get_mut(&hook) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.by_hook.remove(&hook);
+                }
+            }
+        }
+    }
+
+    /// Records that this entity runs the instance, so it gets the instance's events
+    pub fn attach(&mut self, entity: Entity, id: u32) {
+        self.entities.entry(id).or_default().insert(entity);
+    }
+
+    pub fn detach(&mut self, entity: Entity, id: u32) {
+        if let Some(entities) = self.entities.get_mut(&id) {
+            entities.remove(&entity);
+            if entities.is_empty() {
+                self.entities.remove(&id);
+            }
+        }
+    }
+
+    /// Forgets an instance that was dropped
+    pub fn remove(&mut self, id: u32) {
+        self.unsubscribe(id);
+        self.entities.remove(&id);
+    }
+
+    pub fn is_subscribed(&self, id: u32, hook: &str) -> bool {
+        self.hooks.get(&id).map_or(false, |hooks| hooks.contains(hook))
+    }
+
+    /// Every instance that defines the hook, paired with each entity that runs it
+    pub fn subscribed(&self, hook: &str) -> Vec<(u32, Entity)> {
+        self.by_hook.get(hook).into_iter().flatten()
+            .flat_map(|id| self.entities.get(id).into_iter().flatten().map(move |entity| (*id, *entity)))
+            .collect()
+    }
+}
+
+/// The hooks a script subscribes to, which are the `on_` functions it defines globally
+pub fn script_hooks(lua: &Lua) -> Result<HashSet<String>, LuaError> {
+    let mut hooks = HashSet::new();
+    for pair in lua.globals().pairs::<LuaValue, LuaValue>() {
+        if let (LuaValue::String(name), LuaValue::Function(_)) = pair? && let Ok(name) = name.to_str() && name.starts_with(HOOK_PREFIX) {
+            hooks.insert(name.to_string());
+        }
+    }
+    Ok(hooks)
+}
+
+/// Makes sure an event name can be the end of a hook's name, and isn't one the game sends
+pub fn check_event_name(name: &str) -> Result<(), LuaError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Err(LuaError::RuntimeError(format!("Event names can only have letters, numbers and underscores, not {:?}", name)))
+    } else if RESERVED_EVENTS.contains(&name) {
+        Err(LuaError::RuntimeError(format!("The {} event is sent by the game, so scripts can't emit it", name)))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct EventAPI;
+impl LuaMod for EventAPI {
+    fn mod_name() -> &'static str { "Event" }
+    fn register_defs(lua: &Lua, table: &mut LuaTable) -> Result<(), mlua::Error> {
+        table.set("emit", lua.create_function(|lua, (name, args): (String, LuaVariadic<TransVar>)| {
+            check_event_name(&name)?;
+            let world = lua.globals().get::<_, LuaWorld>("world")?;
+            let mut w = world.write();
+            w.resource_mut::<LuaEventQueue>().calls.push(EventCall {
+                hook: Hook { name: format!("{}{}", HOOK_PREFIX, name), args: ManyTransVars(args.into_iter().collect()) },
+            });
+            Ok(())
+        })?)?;
+        Ok(())
     }
 }
 
 /// Called once when this script is being removed from an Entity or its Entity is removed
 /// *params:* ()
-/// todo! implement
-pub const _ON_DROP:        &str = "on_drop";
+/// When the Entity was despawned, `entity` no longer exists by the time this is called
+pub const ON_DROP:        &str = "on_drop";
 
 /// Called after the script fully loads and is processed
 /// *params:* (time: Time)
@@ -34,6 +139,11 @@ pub const _ON_LANG_CHANGE: &str = "on_lang_change";
 /// todo! implement
 pub const _ON_LOAD:        &str = "on_load";
 
+/// Called after the script's file changed on disk and its new source ran in the existing scope
+/// *params:* (old_state: any) -- whatever save_state returned before the reload, or nil without one
+/// Globals the new source doesn't assign keep their old values, so this is only needed for the ones it resets
+pub const ON_RELOAD:      &str = "on_reload";
+
 /// Called when a room is revealed on the map (including at setup for (reveal_before_entry: true))
 /// *params:* (
 ///     name:   String, -- the room's name
@@ -60,10 +170,57 @@ pub const _ON_TAKE_DAMAGE: &str = "on_take_damage";
 /// *params:* ()
 pub const ON_UPDATE:      &str = "on_update";
 
+/// Called right before the script's file is reloaded, to return any state on_reload should receive
+/// *params:* ()
+pub const SAVE_STATE:     &str = "save_state";
+
 pub mod constants {
     /// How many seconds it takes until the next on_update call
     /// 
     /// With 1/15, on_update is called 15 times a second
     pub const ON_UPDATE_DELAY: f32 = 1. / 15.;
+
+    /// How many seconds pass between checks for edited scripts to reload
+    pub const SCRIPT_WATCH_DELAY: f32 = 1.;
 }
 
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use bevy::prelude::Entity;
+
+    use super::{check_event_name, EventSubscribers};
+
+    #[test]
+    fn subscribers() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut subscribers = EventSubscribers::default();
+        subscribers.attach(a, 1);
+        subscribers.attach(b, 1);
+        subscribers.attach(b, 2);
+        subscribers.subscribe(1, HashSet::from(["on_power_outage".to_string()]));
+        subscribers.subscribe(2, HashSet::from(["on_power_outage".to_string(), "on_update".to_string()]));
+
+        let mut subscribed = subscribers.subscribed("on_power_outage");
+        subscribed.sort();
+        assert_eq!(vec![(1, a), (1, b), (2, b)], subscribed);
+        assert_eq!(vec![(2, b)], subscribers.subscribed("on_update"));
+
+        subscribers.subscribe(2, HashSet::from(["on_update".to_string()]));
+        subscribers.detach(b, 1);
+        assert_eq!(vec![(1, a)], subscribers.subscribed("on_power_outage"));
+
+        subscribers.remove(2);
+        assert!(subscribers.subscribed("on_update").is_empty());
+        assert!(!subscribers.is_subscribed(2, "on_update"));
+    }
+
+    #[test]
+    fn event_names() {
+        assert!(check_event_name("power_outage").is_ok());
+        assert!(check_event_name("").is_err());
+        assert!(check_event_name("power outage").is_err());
+        assert!(check_event_name("update").is_err());
+    }
+}
@@ -1,9 +1,11 @@
 use mlua::prelude::*;
-use std::{default::default, collections::HashSet};
+use std::{default::default, collections::{HashMap, HashSet}};
 
-use crate::{data::lua::{LuaWorld, Recipient, ManyTransVars, Hook, TransVar}, system::lua::{SharedInstances, LuaQueue, HookCall}, util::collections::Singleton};
+use bevy::prelude::*;
 
-use super::{ LuaMod, bevy_api::LuaEntity};
+use crate::{data::{level::InRoom, lua::{LuaWorld, Recipient, ManyTransVars, Hook, TransVar}, prefab::Tags}, system::lua::{SharedInstances, LuaQueue, HookCall}, util::collections::Singleton};
+
+use super::{LuaMod, bevy_api::LuaEntity, diagnostics::{profiled_call, report_error}, sandbox::budgeted, wait::call_resumable};
 
 #[derive(Clone, Debug)]
 pub struct MessageBuilder {
@@ -11,6 +13,66 @@ pub struct MessageBuilder {
     pub args:      ManyTransVars,
     pub recipient: Recipient,
 }
+impl MessageBuilder {
+    /// Queues the hook on every recipient's [`LuaQueue`], returning how many calls were queued
+    pub fn deliver(&self, world: &mut World, reply_to: Option<ReplyTo>) -> Result<u32, LuaError> {
+        let recipients: Vec<(Entity, HashSet<u32>)> = match &self.recipient {
+            Recipient::Entity(entity) => vec![(*entity, HashSet::new())],
+            Recipient::Everyone => {
+                let mut query = world.query_filtered::<Entity, With<LuaQueue>>();
+                query.iter(world).map(|entity| (entity, HashSet::new())).collect()
+            },
+            Recipient::NoOne => Vec::new(),
+            Recipient::Script(name) => {
+                let si = world.resource::<SharedInstances>();
+                match si.by_path.get(name) {
+                    Some(entities) => entities.iter()
+                        .map(|(entity, id)| (*entity, HashSet::singleton(si.instance_of(*id))))
+                        .collect(),
+                    None => return Err(LuaError::RuntimeError(format!("No scripts loaded from path {}", name))),
+                }
+            },
+            Recipient::Tag(tag) => {
+                let mut query = world.query::<(Entity, &Tags)>();
+                query.iter(world).filter(|(_, tags)| tags.0.contains(tag)).map(|(entity, _)| (entity, HashSet::new())).collect()
+            },
+            Recipient::Room(room) => {
+                let mut query = world.query::<(Entity, &InRoom)>();
+                query.iter(world).filter(|(_, in_room)| in_room.room == *room).map(|(entity, _)| (entity, HashSet::new())).collect()
+            },
+            Recipient::Children(parent) => world.get::<Children>(*parent)
+                .map(|children| children.iter().map(|child| (*child, HashSet::new())).collect())
+                .unwrap_or_default(),
+        };
+        let mut queued = 0;
+        for (entity, script_ids) in recipients {
+            if let Some(mut queue) = world.get_mut::<LuaQueue>(entity) {
+                queue.calls.push(HookCall {
+                    script_ids,
+                    hook: Hook { name: self.hook_name.clone(), args: self.args.clone() },
+                    reply_to,
+                });
+                if let Some(reply_to) = reply_to {
+                    world.resource_mut::<LuaMessages>().queue(entity, reply_to);
+                }
+                queued += 1;
+            }
+        }
+        Ok(queued)
+    }
+
+    /// Where replies to a message sent from this instance go
+    fn reply_to(lua: &Lua, world: &mut World) -> Result<ReplyTo, LuaError> {
+        let mut messages = world.resource_mut::<LuaMessages>();
+        let id = messages.next_reply_id;
+        messages.next_reply_id += 1;
+        Ok(ReplyTo {
+            id,
+            script_id: lua.globals().get("script_id")?,
+            entity:    lua.globals().get::<_, Option<LuaEntity>>("entity")?.map(|e| e.0),
+        })
+    }
+}
 impl LuaUserData for MessageBuilder {
     fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
         fields.add_field_method_get("hook_name",   |_, this| Ok(this.hook_name.clone()));
@@ -25,53 +87,25 @@ impl LuaUserData for MessageBuilder {
             Ok(this)
         });
         methods.add_method("send", |lua, this, ()| {
-            match &this.recipient {
-                Recipient::Entity(entity) => {
-                    let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
-                    let mut w = world.write();
-                    if let Some(mut ent) = w.get_entity_mut(*entity) {
-                        if let Some(mut queue) = ent.get_mut::<LuaQueue>() {
-                            queue.calls.push(HookCall::next_frame(Hook {
-                                name: this.hook_name.clone(), args: this.args.clone()
-                            }));
-                        }
-                    }
-                },
-                Recipient::Everyone => {
-                    let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
-                    let mut w = world.write();
-                    let mut query = w.query::<&mut LuaQueue>();
-                    for mut queue in query.iter_mut(&mut *w) {
-                        queue.calls.push(HookCall::next_frame(Hook {
-                            name: this.hook_name.clone(), args: this.args.clone()
-                        }));
-                    }
-                },
-                Recipient::NoOne => (),
-                Recipient::Script(name) => {
-                    let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
-                    if let Some(entities) = {
-                        let w = world.read();
-                        let si = w.resource::<SharedInstances>();
-                        si.by_path.get(name).cloned()
-                    } {
-                        for (entity, script_id) in entities.iter() {
-                            let mut w = world.write();
-                            if let Some(mut ent) = w.get_entity_mut(*entity) {
-                                if let Some(mut queue) = ent.get_mut::<LuaQueue>() {
-                                    queue.calls.push(HookCall {
-                                        script_ids: HashSet::singleton(*script_id),
-                                        hook:       Hook { name: this.hook_name.clone(), args: this.args.clone() },
-                                    });
-                                }
-                            }
-                        }
-                    } else {
-                        return Err(LuaError::RuntimeError(format!("No scripts loaded from path {}", name)));
-                    }
-                },
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let mut w = world.write();
+            let reply_to = MessageBuilder::reply_to(lua, &mut w)?;
+            this.deliver(&mut w, Some(reply_to))?;
+            Ok(MessageReply(reply_to.id))
+        });
+        methods.add_method("send_after", |lua, this, seconds: f64| {
+            if !seconds.is_finite() || seconds < 0. {
+                return Err(LuaError::RuntimeError(format!("Can't send a message after {} seconds", seconds)));
             }
-            Ok(())
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let mut w = world.write();
+            let reply_to = MessageBuilder::reply_to(lua, &mut w)?;
+            let due = w.resource::<Time>().elapsed_seconds_f64() + seconds;
+            let mut messages = w.resource_mut::<LuaMessages>();
+            // the delay itself is outstanding, so on_reply can listen before the message is delivered
+            messages.expect(reply_to, 1);
+            messages.delayed.push(DelayedMessage { due, message: this.clone(), reply_to });
+            Ok(MessageReply(reply_to.id))
         });
         methods.add_function("to_entity", |_, (mut this, entity): (MessageBuilder, LuaEntity)| {
             this.recipient = Recipient::Entity(entity.0);
@@ -81,15 +115,33 @@ impl LuaUserData for MessageBuilder {
             this.recipient = Recipient::Everyone;
             Ok(this)
         });
+        methods.add_function("to_children", |lua, (mut this, parent): (MessageBuilder, Option<LuaEntity>)| {
+            let parent = match parent {
+                Some(parent) => parent,
+                None => lua.globals().get::<_, Option<LuaEntity>>("entity")?
+                    .ok_or_else(|| LuaError::RuntimeError("to_children needs an entity when the script isn't running for one".to_string()))?,
+            };
+            this.recipient = Recipient::Children(parent.0);
+            Ok(this)
+        });
+        methods.add_function("to_room", |_, (mut this, room): (MessageBuilder, String)| {
+            this.recipient = Recipient::Room(room);
+            Ok(this)
+        });
         methods.add_function("to_script", |_, (mut this, script_name): (MessageBuilder, String)| {
             this.recipient = Recipient::Script(script_name);
             Ok(this)
         });
+        methods.add_function("to_tag", |_, (mut this, tag): (MessageBuilder, String)| {
+            this.recipient = Recipient::Tag(tag);
+            Ok(this)
+        });
     }
 }
 impl LuaMod for MessageBuilder {
     fn mod_name() -> &'static str { "Message" }
     fn register_defs(lua: &Lua, table: &mut LuaTable) -> Result<(), mlua::Error> {
+        lua.set_app_data(PendingReplies::default());
         table.set("new", lua.create_function(|_, hook_name| {
             Ok(MessageBuilder {
                 hook_name,
@@ -99,4 +151,259 @@ impl LuaMod for MessageBuilder {
         })?)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Where the return values of a message's hooks go, which is the instance and entity that sent it
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ReplyTo {
+    pub id:        u32,
+    pub script_id: u32,
+    pub entity:    Option<Entity>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DelayedMessage {
+    pub due:      f64,
+    pub message:  MessageBuilder,
+    pub reply_to: ReplyTo,
+}
+
+/// Messages waiting to be delivered, and replies waiting to reach the scripts that sent them
+#[derive(Debug, Default, Resource)]
+pub struct LuaMessages {
+    pub next_reply_id: u32,
+    pub delayed:       Vec<DelayedMessage>,
+    /// What each hook call returned, or None if it threw or was dropped before it could
+    pub replies:       Vec<(ReplyTo, Option<ManyTransVars>)>,
+    /// How many queued calls and running hooks each message still has, which have yet to reply
+    pub outstanding:   HashMap<u32, (ReplyTo, u32)>,
+    /// Messages whose sender registered on_reply, so their hooks' return values are worth collecting
+    pub listening:     HashSet<u32>,
+    /// The messages each entity has calls queued for, so they're settled if it's despawned before running them
+    pub queued:        HashMap<Entity, Vec<u32>>,
+}
+impl LuaMessages {
+    /// Counts hook calls or running hooks that will reply to a message
+    pub fn expect(&mut self, reply_to: ReplyTo, count: u32) {
+        self.outstanding.entry(reply_to.id).or_insert((reply_to, 0)).1 += count;
+    }
+
+    /// Counts a queued call, a running hook or a reply as done
+    pub fn settle(&mut self, id: u32) {
+        if let Some((_, count)) = self.outstanding.get_mut(&id) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Counts a call queued on an entity for a message
+    pub fn queue(&mut self, entity: Entity, reply_to: ReplyTo) {
+        self.expect(reply_to, 1);
+        self.queued.entry(entity).or_default().push(reply_to.id);
+    }
+
+    /// Counts a call queued on an entity as done, once it ran or was dropped
+    pub fn unqueue(&mut self, entity: Entity, id: u32) {
+        let found = match self.queued.get_mut(&entity) {
+            Some(ids) => {
+                let found = ids.iter().position(|queued| *queued == id).map(|i| ids.swap_remove(i)).is_some();
+                if ids.is_empty() {
+                    self.queued.remove(&entity);
+                }
+                found
+            },
+            None => false,
+        };
+        if found {
+            self.settle(id);
+        }
+    }
+
+    /// Settles every call still queued on an entity that's gone
+    pub fn drop_queued(&mut self, entity: Entity) {
+        for id in self.queued.remove(&entity).unwrap_or_default() {
+            self.settle(id);
+        }
+    }
+
+    /// Starts collecting replies to a message, returning false if it has nothing left that could reply
+    pub fn listen(&mut self, id: u32) -> bool {
+        if !self.outstanding.contains_key(&id) {
+            return false;
+        }
+        self.listening.insert(id);
+        true
+    }
+
+    /// Where replies to a message go, if its sender is listening for them
+    pub fn reply_to(&self, reply_to: Option<ReplyTo>) -> Option<ReplyTo> {
+        reply_to.filter(|r| self.listening.contains(&r.id))
+    }
+
+    /// Forgets the messages that have nothing left to reply, returning where replies went for those that were listened to
+    pub fn take_settled(&mut self) -> Vec<ReplyTo> {
+        let settled: Vec<u32> = self.outstanding.iter().filter(|(_, (_, count))| *count == 0).map(|(id, _)| *id).collect();
+        settled.into_iter()
+            .filter_map(|id| self.outstanding.remove(&id))
+            .filter(|(reply_to, _)| self.listening.remove(&reply_to.id))
+            .map(|(reply_to, _)| reply_to)
+            .collect()
+    }
+}
+
+/// The callbacks of messages this instance sent, by their reply id
+#[derive(Default)]
+pub struct PendingReplies(HashMap<u32, LuaRegistryKey>);
+
+/// A sent message, which is able to receive what its hooks return
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MessageReply(pub u32);
+impl LuaUserData for MessageReply {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| Ok(format!("reply#{}", this.0)));
+        methods.add_method("on_reply", |lua, this, f: LuaFunction| {
+            let world = lua.globals().get::<_, LuaWorld>("world")?;
+            if !world.write().resource_mut::<LuaMessages>().listen(this.0) {
+                return Ok(*this);
+            }
+            let key = lua.create_registry_value(f)?;
+            let replaced = lua.app_data_mut::<PendingReplies>().and_then(|mut pending| pending.0.insert(this.0, key));
+            if let Some(replaced) = replaced {
+                lua.remove_registry_value(replaced)?;
+            }
+            Ok(*this)
+        });
+    }
+}
+
+/// Counts a hook that started running for a message, so its callback is kept until the hook replies
+pub fn expect_reply(lua: &Lua, reply_to: ReplyTo) -> Result<(), LuaError> {
+    let world = lua.globals().get::<_, LuaWorld>("world")?;
+    world.write().resource_mut::<LuaMessages>().expect(reply_to, 1);
+    Ok(())
+}
+
+/// How deeply tables returned as replies may nest, which also stops tables that contain themselves
+const MAX_REPLY_DEPTH: usize = 16;
+
+/// Sends what a hook returned to the script whose message it was answering
+///
+/// Values that can't be sent, like functions or tables nested too deeply, are logged and counted as no reply,
+/// since the hook itself ran fine
+pub fn send_reply(lua: &Lua, reply_to: ReplyTo, values: Option<LuaMultiValue>) -> Result<(), LuaError> {
+    let args = values.and_then(|values| match reply_values(lua, values) {
+        Ok(args) => Some(ManyTransVars(args)),
+        Err(e) => {
+            warn!("Dropped a reply to message #{} from script id #{}: {}", reply_to.id, reply_to.script_id, e);
+            None
+        },
+    });
+    let world = lua.globals().get::<_, LuaWorld>("world")?;
+    world.write().resource_mut::<LuaMessages>().replies.push((reply_to, args));
+    Ok(())
+}
+
+fn reply_values(lua: &Lua, values: LuaMultiValue) -> Result<Vec<TransVar>, LuaError> {
+    values.into_iter().map(|v| {
+        check_depth(&v, MAX_REPLY_DEPTH)?;
+        TransVar::from_lua(v, lua)
+    }).collect()
+}
+
+fn check_depth(value: &LuaValue, depth: usize) -> Result<(), LuaError> {
+    if let LuaValue::Table(t) = value {
+        if depth == 0 {
+            return Err(LuaError::RuntimeError(format!("tables can't nest more than {} deep", MAX_REPLY_DEPTH)));
+        }
+        for pair in t.clone().pairs::<LuaValue, LuaValue>() {
+            let (k, v) = pair?;
+            check_depth(&k, depth - 1)?;
+            check_depth(&v, depth - 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Calls back this instance with a reply to one of its messages
+pub fn call_reply(lua: &Lua, reply_to: ReplyTo, args: ManyTransVars) -> Result<(), LuaError> {
+    let callback = match lua.app_data_ref::<PendingReplies>() {
+        Some(pending) => match pending.0.get(&reply_to.id) {
+            Some(key) => lua.registry_value::<LuaFunction>(key)?,
+            None      => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+    if let Some(entity) = reply_to.entity {
+        lua.globals().set("entity", LuaEntity(entity))?;
+    }
+    profiled_call(lua, &callback, "on_reply", reply_to.entity, || budgeted(lua, || call_resumable(lua, callback.clone(), args, reply_to.entity, None))).map_err(|e| {
+        report_error(lua, "on_reply", reply_to.entity, &e);
+        e
+    })
+}
+
+/// Drops the callback of a message that has nothing left to reply
+pub fn forget_reply(lua: &Lua, id: u32) -> Result<(), LuaError> {
+    let removed = lua.app_data_mut::<PendingReplies>().and_then(|mut pending| pending.0.remove(&id));
+    if let Some(key) = removed {
+        lua.remove_registry_value(key)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply_to(id: u32) -> ReplyTo {
+        ReplyTo { id, script_id: 1, entity: None }
+    }
+
+    #[test]
+    fn settling() {
+        let mut messages = LuaMessages::default();
+        messages.expect(reply_to(0), 2);
+        messages.expect(reply_to(1), 1);
+        assert!(messages.listen(0));
+        assert!(!messages.listen(2));
+
+        messages.settle(0);
+        messages.settle(1);
+        // the unlistened message is forgotten, but not returned
+        assert!(messages.take_settled().is_empty());
+        assert!(!messages.outstanding.contains_key(&1));
+
+        // a running hook keeps it outstanding until it replies
+        messages.expect(reply_to(0), 1);
+        messages.settle(0);
+        assert!(messages.take_settled().is_empty());
+        messages.settle(0);
+        assert_eq!(messages.take_settled(), vec![reply_to(0)]);
+        assert!(messages.outstanding.is_empty());
+        assert!(messages.listening.is_empty());
+        assert_eq!(messages.reply_to(Some(reply_to(0))), None);
+    }
+
+    #[test]
+    fn queued_calls() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut messages = LuaMessages::default();
+        messages.queue(a, reply_to(0));
+        messages.queue(b, reply_to(0));
+        messages.queue(b, reply_to(1));
+        messages.listen(0);
+        messages.listen(1);
+
+        messages.unqueue(a, 0);
+        // calls that were never queued don't settle anything
+        messages.unqueue(a, 1);
+        assert!(messages.take_settled().is_empty());
+        assert!(!messages.queued.contains_key(&a));
+
+        // b was despawned with both calls still queued
+        messages.drop_queued(b);
+        let mut settled: Vec<u32> = messages.take_settled().into_iter().map(|r| r.id).collect();
+        settled.sort();
+        assert_eq!(settled, vec![0, 1]);
+        assert!(messages.queued.is_empty());
+    }
+}
@@ -1,41 +1,55 @@
+use std::path::{Path, PathBuf};
+
 use bevy::{prelude::*};
 use mlua::prelude::*;
 
-use crate::{data::{stat::{Stat, Pool}, material::{TextureMaterial,}, input::ActionState, formlist::{FormList, InjectCommands}, geometry::{Light, LightAnim, LightKind}, lua::{LuaWorld, TransVar}, palette::{Palette, DynColor}, assetio::VirtualFileOverrides, level::LoadedLevel, rgba::RgbaColor}};
+use crate::{data::{setting::ModSettings, stat::{Stat, Pool}, material::{TextureMaterial,}, input::ActionState, formlist::{FormList, InjectCommands}, geometry::{Light, LightAnim, LightKind}, lua::{LuaWorld, TransVar}, palette::{Palette, DynColor}, assetio::{OverridesLock, VirtualFileOverrides}, level::LoadedLevel, rgba::RgbaColor}, system::module::LoadedModList};
 
-use self::{time::LuaTime, query::{LuaQuery}, random::RandomAPI, log::LogAPI, bevy_api::{math::{LuaVec2, LuaVec3, MathAPI}, image::ImageAPI}, ui::{elem::{UIAPI}, atom::{LuaAtomRef}, text::{TextBuilder, TextStyle}, font::UIFont}, file::FileAPI, message::MessageBuilder};
+use self::{permission::{ScriptPermissions, init_luamod_gated, denied_table}, time::{LuaTime, LuaTimer}, query::{LuaQuery}, random::RandomAPI, log::LogAPI, bevy_api::{math::{LuaVec2, LuaVec3, MathAPI}, image::ImageAPI}, ui::{elem::{UIAPI}, atom::{LuaAtomRef}, text::{TextBuilder, TextStyle}, font::UIFont}, file::FileAPI, debug::DebugAPI, event::EventAPI, message::MessageBuilder, mods::ModsAPI};
 
 pub mod bevy_api;
+pub mod debug;
+pub mod diagnostics;
 pub mod event;
 pub mod file;
 pub mod log;
 pub mod message;
+pub mod mods;
+pub mod permission;
 pub mod query;
 pub mod random;
+pub mod sandbox;
 pub mod time;
 pub mod ui;
+pub mod wait;
 
-pub fn register_lua_mods(lua: &Lua) -> Result<(), LuaError> {
+pub fn register_lua_mods(lua: &Lua, permissions: &ScriptPermissions) -> Result<(), LuaError> {
+    lua.set_app_data(permissions.clone());
     init_luamod::<ActionState>(lua)?;
+    init_luamod::<DebugAPI>(lua)?;
     init_luamod::<DynColor>(lua)?;
+    init_luamod::<EventAPI>(lua)?;
     init_luamod::<UIFont>(lua)?;
     init_luamod::<FormList>(lua)?;
-    init_luamod::<FileAPI>(lua)?;
+    init_luamod_gated::<FileAPI>(lua, permissions, permission::FILE)?;
     init_luamod::<ImageAPI>(lua)?;
     init_luamod::<InjectCommands>(lua)?;
     init_luamod::<LuaAtomRef>(lua)?;
     init_luamod::<LuaQuery>(lua)?;
     init_luamod::<LuaTime>(lua)?;
+    init_luamod::<LuaTimer>(lua)?;
     init_luamod::<LuaVec2>(lua)?;
     init_luamod::<LuaVec3>(lua)?;
     init_luamod::<Light>(lua)?;
     init_luamod::<LightAnim>(lua)?;
     init_luamod::<LightKind>(lua)?;
-    init_luamod::<LoadedLevel>(lua)?;
+    init_luamod_gated::<LoadedLevel>(lua, permissions, permission::WORLD_WRITE)?;
     init_luamod::<LogAPI>(lua)?;
     init_luamod::<MathAPI>(lua)?;
     init_luamod::<MessageBuilder>(lua)?;
-    init_luamod::<Palette>(lua)?;
+    init_luamod::<ModSettings>(lua)?;
+    init_luamod::<ModsAPI>(lua)?;
+    init_luamod_gated::<Palette>(lua, permissions, permission::WORLD_WRITE)?;
     init_luamod::<RandomAPI>(lua)?;
     init_luamod::<TextBuilder>(lua)?;
     init_luamod::<TextStyle>(lua)?;
@@ -45,7 +59,7 @@ pub fn register_lua_mods(lua: &Lua) -> Result<(), LuaError> {
     init_luamod::<RgbaColor>(lua)?;
     init_luamod::<Stat>(lua)?;
     init_luamod::<UIAPI>(lua)?;
-    attach_prelude_lua(lua)?;
+    attach_prelude_lua(lua, permissions)?;
     Ok(())
 }
 
@@ -64,7 +78,7 @@ pub fn init_luamod<T>(lua: &Lua) -> Result<(), mlua::Error> where T: LuaMod {
 
 // Default API
 
-fn attach_prelude_lua(lua: &Lua) -> Result<(), mlua::Error> {
+fn attach_prelude_lua(lua: &Lua, permissions: &ScriptPermissions) -> Result<(), mlua::Error> {
     {
         let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
         let read = world.read();
@@ -72,6 +86,17 @@ fn attach_prelude_lua(lua: &Lua) -> Result<(), mlua::Error> {
         
         let package: LuaTable = lua.globals().get("package")?;
         package.set("path", vfo.lua_path.as_str())?;
+        let searcher_world = world.clone();
+        add_virtual_searcher(lua, vfo.overrides.clone(), move |path| {
+            let w = searcher_world.read();
+            let owner = w.resource::<VirtualFileOverrides>().owner(path)?.name.clone();
+            let granted = w.get_resource::<LoadedModList>().and_then(|ml| ml.permissions.get(&owner).cloned()).unwrap_or_default();
+            Some(ScriptPermissions::new(owner, granted))
+        })?;
+        // require keeps its own reference to the package table, so it still works after the global is hidden
+        if !permissions.has(permission::PACKAGE) {
+            lua.globals().set("package", denied_table(lua, permissions, "package", permission::PACKAGE)?)?;
+        }
     }
 
     lua.globals().set("format", lua.create_function(|_lua, values: LuaMultiValue| {
@@ -80,6 +105,7 @@ fn attach_prelude_lua(lua: &Lua) -> Result<(), mlua::Error> {
     lua.globals().set("string", lua.create_function(|_lua, value: LuaValue| {
         lua_to_string(value)
     })?)?;
+    wait::register_waits(lua)?;
     lua.set_warning_function(|_, str, _| {
         error!("{:?}", str);
         Ok(())
@@ -87,6 +113,39 @@ fn attach_prelude_lua(lua: &Lua) -> Result<(), mlua::Error> {
     Ok(())
 }
 
+/// Lets `require` find scripts through the overrides before package.path, so zipped scripts and overridden `super/` versions are found too
+/// 
+/// Every file a mod provides is an override, so this is also where a script is kept from requiring a mod's file
+/// when that mod lacks any of the script's permissions, since the required chunk would run with them.
+/// `provider` finds the mod providing an overridden asset path, along with its permissions
+fn add_virtual_searcher<F>(lua: &Lua, overrides: OverridesLock, provider: F) -> Result<(), LuaError>
+where F: Fn(&Path) -> Option<ScriptPermissions> + Send + 'static {
+    let package: LuaTable = lua.globals().get("package")?;
+    let searchers: LuaTable = package.get("searchers")?;
+    searchers.raw_insert(2, lua.create_function(move |lua, name: String| {
+        let path = PathBuf::from(format!("{}.lua", name.replace('.', "/")));
+        let file = overrides.read().get(&path).cloned();
+        let Some(file) = file else {
+            return format!("\n\tno virtual file '{}'", path.to_string_lossy()).to_lua(lua);
+        };
+        if let Some(requiring) = lua.app_data_ref::<ScriptPermissions>() && let Some(provider) = provider(&path) && provider.owner != requiring.owner {
+            let mut missing: Vec<&String> = requiring.granted.difference(&provider.granted).collect();
+            if !missing.is_empty() {
+                missing.sort();
+                return Err(LuaError::RuntimeError(format!(
+                    "Mod `{}` can't require {}, since mod `{}` provides it without the {} permission(s) it would run with",
+                    requiring.owner, path.to_string_lossy(), provider.owner,
+                    missing.into_iter().map(|s| format!("`{}`", s)).intersperse(", ".to_string()).collect::<String>(),
+                )));
+            }
+        }
+        let bytes = file.read()
+            .map_err(|e| LuaError::RuntimeError(format!("Unable to read {} from {}: {}", path.to_string_lossy(), file.source_path().to_string_lossy(), e)))?;
+        let chunk = lua.load(&bytes).set_name(&path.to_string_lossy().to_string())?.into_function()?;
+        Ok(LuaValue::Function(chunk))
+    })?)
+}
+
 pub fn lua_to_string(value: LuaValue) -> Result<String, LuaError> {
     match value {
         LuaValue::Boolean(b) => Ok(b.to_string()),
@@ -157,4 +216,66 @@ pub fn format_lua(values: LuaMultiValue) -> Result<String, LuaError> {
         }
     }
     Ok(s)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::Write, path::{Path, PathBuf}, sync::Arc};
+
+    use mlua::prelude::*;
+    use parking_lot::RwLock;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use crate::data::assetio::{super_path, VirtualFile};
+
+    use super::{add_virtual_searcher, permission::{self, ScriptPermissions}, sandbox::sandbox};
+
+    #[test]
+    fn require_from_zip() {
+        let root = std::env::temp_dir().join(format!("bifrons_require_zip_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let archive = root.join("zipped.zip");
+        let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+        zip.start_file("util/strings.lua", FileOptions::default().compression_method(CompressionMethod::Stored)).unwrap();
+        zip.write_all(b"return { shout = function(s) return s:upper() end }").unwrap();
+        zip.finish().unwrap();
+        fs::write(root.join("main.lua"), "return 'core'").unwrap();
+
+        let overrides = HashMap::from([
+            (PathBuf::from("util/strings.lua"), VirtualFile::Zip { archive, entry: "util/strings.lua".to_string() }),
+            (super_path("zipped", Path::new("main.lua")), VirtualFile::File(root.join("main.lua"))),
+        ]);
+        let lua = Lua::new();
+        sandbox(&lua).unwrap();
+        add_virtual_searcher(&lua, Arc::new(RwLock::new(overrides)), |_| None).unwrap();
+        assert_eq!("HI", lua.load(r#"return require("util.strings").shout("hi")"#).eval::<String>().unwrap());
+        assert_eq!("core", lua.load(r#"return require("super/zipped/main")"#).eval::<String>().unwrap());
+        assert!(lua.load(r#"require("util.missing")"#).exec().unwrap_err().to_string().contains("no virtual file 'util/missing.lua'"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn require_override_needs_permissions() {
+        let root = std::env::temp_dir().join(format!("bifrons_require_override_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("strings.lua"), "return { shout = function(s) return s:upper() end }").unwrap();
+
+        let overrides = HashMap::from([(PathBuf::from("util/strings.lua"), VirtualFile::File(root.join("strings.lua")))]);
+        let require_as = |granted: &[&str], provider_granted: &'static [&'static str]| {
+            let lua = Lua::new();
+            sandbox(&lua).unwrap();
+            lua.set_app_data(ScriptPermissions::new("core".to_string(), granted.iter().map(|p| p.to_string()).collect()));
+            add_virtual_searcher(&lua, Arc::new(RwLock::new(overrides.clone())), move |_| {
+                Some(ScriptPermissions::new("sneaky".to_string(), provider_granted.iter().map(|p| p.to_string()).collect::<HashSet<_>>()))
+            }).unwrap();
+            lua.load(r#"return require("util.strings").shout("hi")"#).eval::<String>()
+        };
+        // the overriding mod's code would run with the permissions core's script has
+        let err = require_as(&[permission::FILE, permission::WORLD_WRITE], &[permission::FILE]).unwrap_err().to_string();
+        assert!(err.contains("mod `sneaky` provides it without the `world_write` permission"), "{}", err);
+        assert_eq!("HI", require_as(&[permission::FILE], &[permission::FILE, permission::APP]).unwrap());
+        assert_eq!("HI", require_as(&[], &[]).unwrap());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::HashMap, path::Path};
+
+use bevy::prelude::*;
+use mlua::prelude::*;
+
+use crate::{data::{lua::LuaWorld, lang::ENGLISH, module::{Module, VersionDependency}, assetio::{VirtualFileOverrides, super_path}, fingerprint::ModlistFingerprint}, system::module::{LoadedModList, ReloadMods}, util::ron_options};
+
+use super::{LuaMod, permission::{self, ScriptPermissions}};
+
+#[derive(Default)]
+pub struct ModsAPI;
+impl ModsAPI {
+    /// Describes a loaded mod, with its lines in the given language
+    fn info<'lua>(lua: &'lua Lua, name: &str, module: &Module, lang: Option<&str>) -> Result<LuaTable<'lua>, LuaError> {
+        let table = lua.create_table()?;
+        table.set("name", name)?;
+        table.set("version", module.version.to_string())?;
+        table.set("priority", module.priority)?;
+        table.set("lines", module.lines.get(lang.unwrap_or(ENGLISH)).cloned().unwrap_or_default())?;
+        let dependency_strings = |deps: &HashMap<String, VersionDependency>| deps.iter()
+            .map(|(k, v)| (k.clone(), v.to_string()))
+            .collect::<HashMap<String, String>>();
+        table.set("dependencies", dependency_strings(&module.dependencies))?;
+        table.set("optional_dependencies", dependency_strings(&module.optional_dependencies))?;
+        let mut permissions: Vec<String> = module.permissions.iter().cloned().collect();
+        permissions.sort();
+        table.set("permissions", permissions)?;
+        Ok(table)
+    }
+
+    fn with_mods<F, R>(lua: &Lua, f: F) -> Result<R, LuaError> where F: FnOnce(&LoadedModList, &Assets<Module>) -> Result<R, LuaError> {
+        let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+        let w = world.read();
+        f(w.resource::<LoadedModList>(), w.resource::<Assets<Module>>())
+    }
+
+    fn with_fingerprint<F, R>(lua: &Lua, f: F) -> Result<R, LuaError> where F: FnOnce(&ModlistFingerprint) -> Result<R, LuaError> {
+        let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+        let w = world.read();
+        f(w.resource::<LoadedModList>().fingerprint(w.resource::<VirtualFileOverrides>()))
+    }
+}
+impl LuaMod for ModsAPI {
+    fn mod_name() -> &'static str { "Mods" }
+    fn register_defs(lua: &Lua, table: &mut LuaTable) -> Result<(), mlua::Error> {
+        table.set("compare_fingerprint", lua.create_function(|lua, theirs: String| {
+            let theirs: ModlistFingerprint = ron_options().from_str(&theirs)
+                .map_err(|e| LuaError::RuntimeError(format!("Invalid modlist fingerprint: {}", e)))?;
+            ModsAPI::with_fingerprint(lua, |ours| {
+                Ok(ours.diff(&theirs).into_iter().map(|d| d.to_string()).collect::<Vec<_>>())
+            })
+        })?)?;
+        table.set("fingerprint", lua.create_function(|lua, ()| {
+            ModsAPI::with_fingerprint(lua, |ours| {
+                ron::to_string(ours).map_err(|e| LuaError::RuntimeError(e.to_string()))
+            })
+        })?)?;
+        table.set("fingerprint_hash", lua.create_function(|lua, ()| {
+            ModsAPI::with_fingerprint(lua, |ours| Ok(format!("{:016x}", ours.hash())))
+        })?)?;
+        table.set("get", lua.create_function(|lua, (name, lang): (String, Option<String>)| {
+            ModsAPI::with_mods(lua, |loaded_ml, mods| {
+                if !loaded_ml.load_order.iter().flatten().any(|n| n == &name) {
+                    return Ok(None);
+                }
+                match loaded_ml.handles.get(&name).and_then(|h| mods.get(h)) {
+                    Some(module) => Ok(Some(ModsAPI::info(lua, &name, module, lang.as_deref())?)),
+                    None         => Ok(None),
+                }
+            })
+        })?)?;
+        table.set("has", lua.create_function(|lua, (name, version): (String, Option<String>)| {
+            let dependency = match version {
+                Some(version) => version.parse::<VersionDependency>().map_err(LuaError::RuntimeError)?,
+                None          => VersionDependency::Any,
+            };
+            ModsAPI::with_mods(lua, |loaded_ml, mods| {
+                if !loaded_ml.load_order.iter().flatten().any(|n| n == &name) {
+                    return Ok(false);
+                }
+                Ok(loaded_ml.handles.get(&name)
+                    .and_then(|h| mods.get(h))
+                    .map(|m| m.is_version_valid(dependency))
+                    .unwrap_or(false))
+            })
+        })?)?;
+        table.set("list", lua.create_function(|lua, lang: Option<String>| {
+            ModsAPI::with_mods(lua, |loaded_ml, mods| {
+                let mut list = Vec::new();
+                for name in loaded_ml.load_order.iter().flatten() {
+                    if let Some(module) = loaded_ml.handles.get(name).and_then(|h| mods.get(h)) {
+                        list.push(ModsAPI::info(lua, name, module, lang.as_deref())?);
+                    }
+                }
+                Ok(list)
+            })
+        })?)?;
+        table.set("load_order", lua.create_function(|lua, ()| {
+            ModsAPI::with_mods(lua, |loaded_ml, _| Ok(loaded_ml.load_order.clone()))
+        })?)?;
+        table.set("providers", lua.create_function(|lua, path: String| {
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let w = world.read();
+            let providers = w.resource::<VirtualFileOverrides>().providers.get(Path::new(&path))
+                .map(|p| p.iter().map(|e| e.name.clone()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            Ok(providers)
+        })?)?;
+        table.set("reload", lua.create_function(|lua, ()| {
+            ScriptPermissions::check(lua, "Mods.reload", permission::APP)?;
+            let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
+            let mut w = world.write();
+            w.send_event(ReloadMods);
+            Ok(())
+        })?)?;
+        table.set("super_path", lua.create_function(|lua, path: String| {
+            let owner = lua.app_data_ref::<ScriptPermissions>()
+                .map(|p| p.owner.clone())
+                .ok_or_else(|| LuaError::RuntimeError("Mods.super_path can only be used by scripts that belong to a mod".to_string()))?;
+            Ok(super_path(&owner, Path::new(&path)).to_string_lossy().to_string())
+        })?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+
+use mlua::prelude::*;
+
+use super::{LuaMod, init_luamod, lua_to_string};
+
+/// Opening native dialogs and reading files outside of the asset server
+pub const FILE: &str = "file";
+/// Controlling the app itself, like exiting the game
+pub const APP: &str = "app";
+/// Reading or changing the `package` table, which controls where `require` looks for scripts
+pub const PACKAGE: &str = "package";
+/// Adding assets and changing state in the world, such as levels, palettes and entities' components
+pub const WORLD_WRITE: &str = "world_write";
+
+/// The permissions granted to a script by the `.mod.ron` of the mod that owns it
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScriptPermissions {
+    pub owner:   String,
+    pub granted: HashSet<String>,
+}
+impl ScriptPermissions {
+    pub fn new(owner: String, granted: HashSet<String>) -> Self {
+        ScriptPermissions { owner, granted }
+    }
+
+    pub fn has(&self, permission: &str) -> bool {
+        self.granted.contains(permission)
+    }
+
+    pub fn denied(&self, what: &str, permission: &str) -> LuaError {
+        LuaError::RuntimeError(format!(
+            "{} requires the `{}` permission, but mod `{}` does not list it in the `permissions` of its .mod.ron",
+            what, permission, self.owner,
+        ))
+    }
+
+    /// Errors unless the mod owning the calling script was granted `permission`
+    pub fn check(lua: &Lua, what: &str, permission: &str) -> Result<(), LuaError> {
+        match lua.app_data_ref::<ScriptPermissions>() {
+            Some(perms) if perms.has(permission) => Ok(()),
+            Some(perms) => Err(perms.denied(what, permission)),
+            None        => Err(ScriptPermissions::default().denied(what, permission)),
+        }
+    }
+}
+
+/// Registers `T` if its permission was granted, or otherwise a stand-in table that errors on any access
+pub fn init_luamod_gated<T>(lua: &Lua, permissions: &ScriptPermissions, permission: &'static str) -> Result<(), mlua::Error> where T: LuaMod {
+    if permissions.has(permission) {
+        init_luamod::<T>(lua)
+    } else {
+        lua.globals().set(T::mod_name().to_string(), denied_table(lua, permissions, T::mod_name(), permission)?)
+    }
+}
+
+pub fn denied_table<'lua>(lua: &'lua Lua, permissions: &ScriptPermissions, name: &'static str, permission: &'static str) -> Result<LuaTable<'lua>, mlua::Error> {
+    let permissions = permissions.clone();
+    let deny = move |key: LuaValue| -> LuaError {
+        let key = lua_to_string(key).unwrap_or_else(|_| "?".to_string());
+        permissions.denied(&format!("{}.{}", name, key), permission)
+    };
+    let deny_newindex = deny.clone();
+
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.create_function(move |_, (_, key): (LuaValue, LuaValue)| -> Result<(), LuaError> {
+        Err(deny(key))
+    })?)?;
+    meta.set("__newindex", lua.create_function(move |_, (_, key, _): (LuaValue, LuaValue, LuaValue)| -> Result<(), LuaError> {
+        Err(deny_newindex(key))
+    })?)?;
+    let table = lua.create_table()?;
+    table.set_metatable(Some(meta));
+    Ok(table)
+}
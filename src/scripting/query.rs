@@ -5,6 +5,13 @@ use crate::data::{lua::LuaWorld};
 
 use super::{LuaMod, bevy_api::LuaEntity};
 
+/// Finds a registered type by its short name, like `Transform`, or its full path
+pub fn type_registration<'r>(registry: &'r TypeRegistry, type_name: &str) -> Result<&'r TypeRegistration, LuaError> {
+    registry.get_with_short_name(type_name)
+        .or_else(|| registry.get_with_name(type_name))
+        .ok_or_else(|| LuaError::RuntimeError(format!("Type {} doesn't exist or wasn't registered", type_name)))
+}
+
 #[derive(Clone)]
 pub struct LuaQuery {
     name:    Option<String>,
@@ -17,13 +24,7 @@ impl LuaQuery {
         let registry = w.get_resource::<AppTypeRegistry>().unwrap().read();
         let mut types = Vec::new();
         for type_name in field(self) {
-            if let Some(reg) = registry.get_with_short_name(type_name)
-                .or_else(|| registry.get_with_name(type_name))
-                .map(|registration| registration.clone()) {
-                types.push(reg);
-            } else {
-                return Err(LuaError::RuntimeError(format!("Type {} doesn't exist or wasn't registered", type_name)));
-            }
+            types.push(type_registration(&registry, type_name)?.clone());
         }
         Ok(types)
     }
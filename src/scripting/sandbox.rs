@@ -0,0 +1,163 @@
+use std::{cell::Cell, time::{Duration, Instant}};
+
+use mlua::{prelude::*, HookTriggers};
+
+/// How much memory a single instance can allocate before its allocations start failing
+pub const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+/// How long a single call from the game into an instance can run before it's aborted
+pub const TIME_BUDGET: Duration = Duration::from_millis(250);
+/// How many instructions run between checks of the time budget
+const BUDGET_CHECK_INTERVAL: u32 = 10_000;
+
+/// Globals that reach outside the game or into the VM itself
+const REMOVED_GLOBALS: [&str; 5] = ["collectgarbage", "debug", "dofile", "io", "loadfile"];
+/// The only `os` functions scripts can use, since the rest can run commands or touch files
+const SAFE_OS: [&str; 4] = ["clock", "date", "difftime", "time"];
+
+/// When the call currently running in an instance has to finish by
+#[derive(Default)]
+pub struct ScriptBudget {
+    deadline: Cell<Option<Instant>>,
+}
+
+/// Strips the standard library down to what can't harm the player's machine, and limits the instance's memory and run time
+///
+/// Must run before the game's modules are registered, so the `require` searcher they add stays in place
+pub fn sandbox(lua: &Lua) -> Result<(), LuaError> {
+    let globals = lua.globals();
+    for name in REMOVED_GLOBALS {
+        globals.set(name, LuaValue::Nil)?;
+    }
+    let os: LuaTable = globals.get("os")?;
+    let safe_os = lua.create_table()?;
+    for name in SAFE_OS {
+        safe_os.set(name, os.get::<_, LuaValue>(name)?)?;
+    }
+    globals.set("os", safe_os)?;
+    // binary chunks can corrupt the VM, so only source can be loaded
+    let load = lua.create_registry_value(globals.get::<_, LuaFunction>("load")?)?;
+    globals.set("load", lua.create_function(move |lua, args: LuaMultiValue| {
+        // an explicit nil env is different from none, so only the mode is touched
+        let mut args = args.into_vec();
+        args.resize(args.len().max(3), LuaValue::Nil);
+        args[2] = "t".to_lua(lua)?;
+        lua.registry_value::<LuaFunction>(&load)?.call::<_, LuaMultiValue>(LuaMultiValue::from_vec(args))
+    })?)?;
+    let string: LuaTable = globals.get("string")?;
+    string.set("dump", LuaValue::Nil)?;
+    let package: LuaTable = globals.get("package")?;
+    package.set("cpath", "")?;
+    package.set("loadlib", LuaValue::Nil)?;
+    // only the preload and Lua file searchers are kept, since the rest load C libraries
+    let searchers: LuaTable = package.get("searchers")?;
+    let (preload, lua_files): (LuaFunction, LuaFunction) = (searchers.raw_get(1)?, searchers.raw_get(2)?);
+    package.set("searchers", lua.create_sequence_from([preload, lua_files])?)?;
+
+    lua.set_memory_limit(MEMORY_LIMIT)?;
+    lua.set_app_data(ScriptBudget::default());
+    lua.set_hook(HookTriggers { every_nth_instruction: Some(BUDGET_CHECK_INTERVAL), ..Default::default() }, |lua, _| {
+        match lua.app_data_ref::<ScriptBudget>().and_then(|b| b.deadline.get()) {
+            Some(deadline) if Instant::now() > deadline => Err(LuaError::RuntimeError(format!(
+                "Script ran for longer than its {}ms budget and was aborted", TIME_BUDGET.as_millis(),
+            ))),
+            _ => Ok(()),
+        }
+    })?;
+    Ok(())
+}
+
+/// Runs `f` as one call from the game into this instance, which is aborted once it runs past [`TIME_BUDGET`]
+///
+/// Calls made while another is already running share its deadline
+pub fn budgeted<R>(lua: &Lua, f: impl FnOnce() -> Result<R, LuaError>) -> Result<R, LuaError> {
+    let started = match lua.app_data_ref::<ScriptBudget>() {
+        Some(budget) if budget.deadline.get().is_none() => {
+            budget.deadline.set(Some(Instant::now() + TIME_BUDGET));
+            true
+        },
+        _ => false,
+    };
+    let result = f();
+    if started && let Some(budget) = lua.app_data_ref::<ScriptBudget>() {
+        budget.deadline.set(None);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use mlua::prelude::*;
+
+    use super::{budgeted, sandbox, ScriptBudget, REMOVED_GLOBALS, SAFE_OS};
+
+    fn sandboxed() -> Lua {
+        let lua = Lua::new();
+        sandbox(&lua).unwrap();
+        lua
+    }
+
+    #[test]
+    fn removed_globals() {
+        let lua = sandboxed();
+        for name in REMOVED_GLOBALS {
+            assert_eq!(LuaValue::Nil, lua.globals().get::<_, LuaValue>(name).unwrap(), "{} should be removed", name);
+        }
+        let mut os: Vec<String> = lua.globals().get::<_, LuaTable>("os").unwrap().pairs::<String, LuaValue>().map(|p| p.unwrap().0).collect();
+        os.sort();
+        assert_eq!(SAFE_OS.to_vec(), os);
+        assert!(lua.load("return string.dump == nil and package.loadlib == nil and os.execute == nil").eval::<bool>().unwrap());
+    }
+
+    #[test]
+    fn load_rejects_binary() {
+        let binary = Lua::new().load("return 1").into_function().unwrap().dump(false);
+        let lua = sandboxed();
+        lua.globals().set("binary", lua.create_string(&binary).unwrap()).unwrap();
+        let (chunk, err): (LuaValue, String) = lua.load("return load(binary)").eval().unwrap();
+        assert_eq!(LuaValue::Nil, chunk);
+        assert!(err.contains("binary"), "{}", err);
+        assert_eq!(2, lua.load(r#"return load("return 2")()"#).eval::<i64>().unwrap());
+    }
+
+    #[test]
+    fn time_budget() {
+        let lua = sandboxed();
+        let err = budgeted(&lua, || lua.load("while true do end").exec()).unwrap_err();
+        assert!(err.to_string().contains("budget"), "{}", err);
+        // the deadline is cleared, so the next call gets a budget of its own
+        assert_eq!(None, lua.app_data_ref::<ScriptBudget>().unwrap().deadline.get());
+        assert_eq!(2, budgeted(&lua, || lua.load("return 1 + 1").eval::<i64>()).unwrap());
+    }
+
+    #[test]
+    fn memory_limit() {
+        let lua = sandboxed();
+        let err = lua.load(r#"
+            local hoard = {}
+            for i = 1, 1000 do
+                hoard[i] = string.rep("x", 1024 * 1024) .. i
+            end
+        "#).exec().unwrap_err();
+        assert!(matches!(err, LuaError::MemoryError(_)), "{}", err);
+        assert_eq!(2, lua.load("return 1 + 1").eval::<i64>().unwrap());
+    }
+
+    #[test]
+    fn require_from_disk() {
+        let root = std::env::temp_dir().join(format!("bifrons_sandbox_require_{}", std::process::id()));
+        fs::create_dir_all(root.join("systems")).unwrap();
+        fs::write(root.join("systems/registry.lua"), "return { answer = 42 }").unwrap();
+
+        let lua = Lua::new();
+        sandbox(&lua).unwrap();
+        let package: LuaTable = lua.globals().get("package").unwrap();
+        package.set("path", format!("{}/?.lua", root.to_string_lossy())).unwrap();
+        let answer: i64 = lua.load(r#"return require("systems/registry").answer"#).eval().unwrap();
+        assert_eq!(42, answer);
+        assert_eq!(2, package.get::<_, LuaTable>("searchers").unwrap().raw_len());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use bevy::prelude::*;
 use bevy_inspector_egui::prelude::*;
 use mlua::prelude::*;
@@ -5,7 +7,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::data::lua::LuaWorld;
 
-use super::{LuaMod};
+use super::{LuaMod, bevy_api::LuaEntity, diagnostics::{profiled_call, report_error}, sandbox::budgeted, wait::call_resumable};
 
 #[derive(Clone, Copy, Debug, Default, Deserialize, Inspectable, PartialEq, Resource, Serialize, Reflect)]
 pub struct LuaTime {
@@ -45,9 +47,214 @@ pub struct LuaTimer {
     pub duration: f64,
     pub repeat:   bool,
 }
-// impl LuaMod for LuaTimer {
-//     fn mod_name() -> &'static str { "Timer" }
-//     fn register_defs(lua: &Lua, table: &mut LuaTable) -> Result<(), mlua::Error> {
-//         Ok(())
-//     }
-// }
\ No newline at end of file
+impl LuaTimer {
+    /// When this timer fires next
+    pub fn end(&self) -> f64 {
+        self.start + self.duration
+    }
+}
+impl LuaMod for LuaTimer {
+    fn mod_name() -> &'static str { "Timer" }
+    fn register_defs(lua: &Lua, table: &mut LuaTable) -> Result<(), mlua::Error> {
+        lua.set_app_data(LuaTimers::default());
+        table.set("after", lua.create_function(|lua, (seconds, f): (f64, LuaFunction)| {
+            if !seconds.is_finite() || seconds < 0. {
+                return Err(LuaError::RuntimeError(format!("Timers need a duration of 0 or more seconds, not {}", seconds)));
+            }
+            start_timer(lua, seconds, false, f)
+        })?)?;
+        table.set("every", lua.create_function(|lua, (seconds, f): (f64, LuaFunction)| {
+            if !seconds.is_finite() || seconds <= 0. {
+                return Err(LuaError::RuntimeError(format!("Repeating timers need a duration above 0, not {}", seconds)));
+            }
+            start_timer(lua, seconds, true, f)
+        })?)?;
+        Ok(())
+    }
+}
+
+struct ActiveTimer {
+    timer:     LuaTimer,
+    paused_at: Option<f64>,
+    entity:    Option<Entity>,
+    callback:  LuaRegistryKey,
+}
+
+/// Every timer started by an instance, which go away along with it
+#[derive(Default)]
+pub struct LuaTimers {
+    next_id: u32,
+    timers:  HashMap<u32, ActiveTimer>,
+}
+
+fn elapsed(lua: &Lua) -> Result<f64, LuaError> {
+    let world = lua.globals().get::<_, LuaWorld>("world")?;
+    let w = world.read();
+    Ok(w.resource::<Time>().elapsed_seconds_f64())
+}
+
+fn start_timer(lua: &Lua, duration: f64, repeat: bool, f: LuaFunction) -> Result<LuaTimerRef, LuaError> {
+    let start = elapsed(lua)?;
+    let entity = lua.globals().get::<_, Option<LuaEntity>>("entity")?.map(|e| e.0);
+    let callback = lua.create_registry_value(f)?;
+    let mut timers = lua.app_data_mut::<LuaTimers>().unwrap();
+    let id = timers.next_id;
+    timers.next_id += 1;
+    timers.timers.insert(id, ActiveTimer { timer: LuaTimer { start, duration, repeat }, paused_at: None, entity, callback });
+    Ok(LuaTimerRef(id))
+}
+
+/// A timer started by `Timer.after` or `Timer.every`, which stays valid after it finishes or is cancelled
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LuaTimerRef(pub u32);
+impl LuaTimerRef {
+    fn with<R>(&self, lua: &Lua, f: impl FnOnce(&mut ActiveTimer) -> R) -> Option<R> {
+        lua.app_data_mut::<LuaTimers>().and_then(|mut timers| timers.timers.get_mut(&self.0).map(f))
+    }
+
+    pub fn cancel(&self, lua: &Lua) -> Result<(), LuaError> {
+        let removed = lua.app_data_mut::<LuaTimers>().and_then(|mut timers| timers.timers.remove(&self.0));
+        if let Some(timer) = removed {
+            lua.remove_registry_value(timer.callback)?;
+        }
+        Ok(())
+    }
+}
+impl LuaUserData for LuaTimerRef {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("is_active", |lua, this| Ok(this.with(lua, |_| ()).is_some()));
+        fields.add_field_method_get("is_paused", |lua, this| Ok(this.with(lua, |t| t.paused_at.is_some()).unwrap_or(false)));
+        fields.add_field_method_get("remaining", |lua, this| {
+            let now = elapsed(lua)?;
+            Ok(this.with(lua, |t| t.timer.end() - t.paused_at.unwrap_or(now)).map(|r| r.max(0.)))
+        });
+    }
+
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| Ok(format!("timer#{}", this.0)));
+        methods.add_method("cancel", |lua, this, ()| this.cancel(lua));
+        methods.add_method("pause", |lua, this, ()| {
+            let now = elapsed(lua)?;
+            this.with(lua, |t| if t.paused_at.is_none() { t.paused_at = Some(now) });
+            Ok(())
+        });
+        methods.add_method("resume", |lua, this, ()| {
+            let now = elapsed(lua)?;
+            this.with(lua, |t| if let Some(paused_at) = t.paused_at.take() { t.timer.start += now - paused_at });
+            Ok(())
+        });
+    }
+}
+
+/// Calls back every timer of this instance that's due by `now`, then restarts the repeating ones and forgets the rest
+pub fn fire_timers(lua: &Lua, now: f64) -> Result<(), LuaError> {
+    let due: Vec<(u32, Option<Entity>)> = match lua.app_data_ref::<LuaTimers>() {
+        Some(timers) => {
+            let mut due: Vec<(u32, f64, Option<Entity>)> = timers.timers.iter()
+                .filter(|(_, t)| t.paused_at.is_none() && t.timer.end() <= now)
+                .map(|(id, t)| (*id, t.timer.end(), t.entity))
+                .collect();
+            due.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+            due.into_iter().map(|(id, _, entity)| (id, entity)).collect()
+        },
+        None => return Ok(()),
+    };
+    for (id, entity) in due {
+        // an earlier callback might have cancelled this one
+        let Some(callback) = LuaTimerRef(id).with(lua, |t| lua.registry_value::<LuaFunction>(&t.callback)) else {
+            continue;
+        };
+        let previous: LuaValue = lua.globals().get("entity")?;
+        if let Some(entity) = entity {
+            lua.globals().set("entity", LuaEntity(entity))?;
+        }
+        let _ = callback.and_then(|callback| {
+            profiled_call(lua, &callback, "timer", entity, || budgeted(lua, || call_resumable(lua, callback.clone(), LuaTimerRef(id), entity, None)))
+        }).map_err(|e| {
+            report_error(lua, "timer", entity, &e);
+        });
+        lua.globals().set("entity", previous)?;
+        let finished = LuaTimerRef(id).with(lua, |t| {
+            if t.timer.repeat {
+                // a timer that fell behind, like after a long frame, fires once instead of catching up
+                t.timer.start = if t.timer.end() + t.timer.duration <= now { now } else { t.timer.end() };
+                false
+            } else {
+                true
+            }
+        });
+        if finished == Some(true) {
+            LuaTimerRef(id).cancel(lua)?;
+        }
+    }
+    Ok(())
+}
+
+/// Cancels every timer started for this entity, so they don't fire after it's gone
+pub fn forget_entity_timers(lua: &Lua, entity: Entity) -> Result<(), LuaError> {
+    let ids: Vec<u32> = match lua.app_data_ref::<LuaTimers>() {
+        Some(timers) => timers.timers.iter().filter(|(_, t)| t.entity == Some(entity)).map(|(id, _)| *id).collect(),
+        None => return Ok(()),
+    };
+    for id in ids {
+        LuaTimerRef(id).cancel(lua)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scripting::init_luamod;
+
+    use super::*;
+
+    #[test]
+    fn firing_timers() {
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        let target = world.spawn_empty().id();
+        let lua = Lua::new();
+        lua.globals().set("world", unsafe { LuaWorld::new(&mut world) }).unwrap();
+        init_luamod::<LuaTimer>(&lua).unwrap();
+
+        lua.globals().set("entity", LuaEntity(target)).unwrap();
+        lua.load(r#"
+            fired = {}
+            Timer.after(2, function() table.insert(fired, "late") end)
+            Timer.after(1, function() table.insert(fired, "early") end)
+            Timer.every(1, function() table.insert(fired, "tick"); seen = entity end)
+            local doomed
+            Timer.after(1, function() table.insert(fired, "cancel"); doomed:cancel() end)
+            doomed = Timer.after(1.5, function() table.insert(fired, "doomed") end)
+        "#).exec().unwrap();
+        lua.globals().set("entity", LuaValue::Nil).unwrap();
+        let fired = |lua: &Lua| -> Vec<String> { lua.globals().get::<_, LuaTable>("fired").unwrap().sequence_values().map(Result::unwrap).collect() };
+
+        // due timers fire in the order they were due, and a cancelled one is skipped even though it was due
+        fire_timers(&lua, 2.5).unwrap();
+        assert_eq!(vec!["early", "tick", "cancel", "late"], fired(&lua));
+        assert_eq!(target, lua.globals().get::<_, LuaEntity>("seen").unwrap().0);
+        assert_eq!(LuaValue::Nil, lua.globals().get::<_, LuaValue>("entity").unwrap());
+
+        // the repeating timer fell behind, so it fired once and counts its next second from then
+        lua.load("fired = {}").exec().unwrap();
+        fire_timers(&lua, 3.).unwrap();
+        assert!(fired(&lua).is_empty());
+        fire_timers(&lua, 3.5).unwrap();
+        assert_eq!(vec!["tick"], fired(&lua));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        let lua = Lua::new();
+        lua.globals().set("world", unsafe { LuaWorld::new(&mut world) }).unwrap();
+        init_luamod::<LuaTimer>(&lua).unwrap();
+
+        for call in ["Timer.after(0/0, print)", "Timer.after(math.huge, print)", "Timer.after(-1, print)", "Timer.every(0/0, print)", "Timer.every(0, print)"] {
+            assert!(lua.load(call).exec().is_err(), "{} should throw", call);
+        }
+        assert!(lua.load("Timer.after(0, print)").exec().is_ok());
+    }
+}
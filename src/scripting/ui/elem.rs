@@ -6,7 +6,7 @@ use egui::panel::TopBottomSide;
 use indexmap::IndexMap;
 use mlua::prelude::*;
 
-use crate::{data::{lua::{LuaWorld, Any3}, palette::DynColor}, scripting::{bevy_api::{math::LuaVec2, handle::LuaHandle}, LuaMod}};
+use crate::{data::{lua::{LuaWorld, Any3}, palette::DynColor}, scripting::{bevy_api::{math::LuaVec2, handle::LuaHandle}, permission::{self, ScriptPermissions}, LuaMod}};
 
 use super::{atom::{OrAtom}, text::TextBuilder};
 
@@ -317,6 +317,7 @@ impl LuaMod for UIAPI {
         })?)?;
 
         table.set("queue_app_exit", lua.create_function(|lua, ()| {
+            ScriptPermissions::check(lua, "UI.queue_app_exit", permission::APP)?;
             let world = lua.globals().get::<_, LuaWorld>("world").unwrap();
             let mut w = world.write();
             w.send_event(AppExit);
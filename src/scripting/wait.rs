@@ -0,0 +1,223 @@
+use bevy::prelude::*;
+use mlua::prelude::*;
+
+use crate::data::lua::LuaWorld;
+
+use super::{bevy_api::LuaEntity, diagnostics::{defined_in, profiled_in, report_error}, message::{expect_reply, send_reply, ReplyTo}, sandbox::budgeted};
+
+/// What a script's coroutine is waiting on before it can resume
+#[derive(Clone, Debug, PartialEq)]
+pub enum WaitUntil {
+    /// Until `Time.elapsed()` reaches this
+    Elapsed(f64),
+    /// Until this many more ticks of the scheduler pass
+    Ticks(u32),
+    /// Until a hook call with this name reaches the script
+    Message(String),
+}
+impl WaitUntil {
+    /// Counts one tick of the scheduler, returning true once the wait is over
+    pub fn tick(&mut self, elapsed: f64) -> bool {
+        match self {
+            WaitUntil::Elapsed(until) => elapsed >= *until,
+            WaitUntil::Ticks(n)       => {
+                *n = n.saturating_sub(1);
+                *n == 0
+            },
+            WaitUntil::Message(_)     => false,
+        }
+    }
+}
+impl LuaUserData for WaitUntil {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| Ok(format!("wait{{{:?}}}", this)));
+    }
+}
+
+struct Waiting {
+    thread: LuaRegistryKey,
+    entity: Option<Entity>,
+    until:  WaitUntil,
+    /// Where to send what the coroutine returns, if it's answering a message
+    reply:  Option<ReplyTo>,
+    /// The script that defined the coroutine's function, when the instance's scope is shared by several
+    source: Option<String>,
+}
+
+/// Every coroutine of an instance that's waiting, in the order they started waiting
+#[derive(Default)]
+pub struct WaitingThreads(Vec<Waiting>);
+
+// coroutine.yield can't be called from Rust, so the waits are thin Lua wrappers around Rust functions building the WaitUntil
+const WAITS: &str = r#"
+local elapsed, ticks, message = ...
+local yield, isyieldable = coroutine.yield, coroutine.isyieldable
+
+local function check(name)
+    if not isyieldable() then
+        error(name .. " can only be used in hooks (other than on_update) and the functions they call", 3)
+    end
+end
+
+function wait(seconds)
+    check("wait")
+    return yield(elapsed(seconds))
+end
+
+function wait_frames(n)
+    check("wait_frames")
+    return yield(ticks(n))
+end
+
+function wait_message(name)
+    check("wait_message")
+    return yield(message(name))
+end
+
+function wait_for(handle)
+    check("wait_for")
+    local loaded = false
+    handle:on_load(function() loaded = true end)
+    while not loaded do
+        yield(ticks(1))
+    end
+    return handle
+end
+"#;
+
+pub fn register_waits(lua: &Lua) -> Result<(), LuaError> {
+    lua.set_app_data(WaitingThreads::default());
+    let elapsed = lua.create_function(|lua, seconds: f64| {
+        let world = lua.globals().get::<_, LuaWorld>("world")?;
+        let w = world.read();
+        Ok(WaitUntil::Elapsed(w.resource::<Time>().elapsed_seconds_f64() + seconds))
+    })?;
+    let ticks = lua.create_function(|_, n: u32| Ok(WaitUntil::Ticks(n.max(1))))?;
+    let message = lua.create_function(|_, name: String| Ok(WaitUntil::Message(name)))?;
+    lua.load(WAITS).set_name("waits")?.call((elapsed, ticks, message))
+}
+
+/// Calls `f` as a coroutine, so it's able to wait; if it does, it's resumed later through [`resume_waiting`]
+/// 
+/// Whatever `f` returns once it finishes is sent to `reply`, or nothing if it throws
+pub fn call_resumable<'lua>(lua: &'lua Lua, f: LuaFunction<'lua>, args: impl ToLuaMulti<'lua>, entity: Option<Entity>, reply: Option<ReplyTo>) -> Result<(), LuaError> {
+    if let Some(reply) = reply {
+        expect_reply(lua, reply)?;
+    }
+    let source = defined_in(lua, &f);
+    let thread = lua.create_thread(f)?;
+    let resumed = thread.resume::<_, LuaMultiValue>(args);
+    finish_or_park(lua, thread, resumed, entity, reply, source)
+}
+
+/// Sends the return values of a coroutine that finished or threw to `reply`, and keeps one that yielded to resume it later
+fn finish_or_park<'lua>(
+    lua:     &'lua Lua,
+    thread:  LuaThread<'lua>,
+    resumed: Result<LuaMultiValue<'lua>, LuaError>,
+    entity:  Option<Entity>,
+    reply:   Option<ReplyTo>,
+    source:  Option<String>,
+) -> Result<(), LuaError> {
+    match resumed {
+        Ok(values) if thread.status() == LuaThreadStatus::Resumable => {
+            park(lua, thread, values.into_iter().next().unwrap_or(LuaValue::Nil), entity, reply, source)
+        },
+        Ok(values) => match reply {
+            Some(reply) => send_reply(lua, reply, Some(values)),
+            None        => Ok(()),
+        },
+        Err(err) => {
+            if let Some(reply) = reply {
+                send_reply(lua, reply, None)?;
+            }
+            Err(err)
+        },
+    }
+}
+
+/// Keeps a coroutine that yielded, to resume it once what it's waiting on happens
+fn park<'lua>(
+    lua:     &'lua Lua,
+    thread:  LuaThread<'lua>,
+    yielded: LuaValue<'lua>,
+    entity:  Option<Entity>,
+    reply:   Option<ReplyTo>,
+    source:  Option<String>,
+) -> Result<(), LuaError> {
+    // anything else yielded straight from a hook just waits for the next tick
+    let until = match yielded {
+        LuaValue::UserData(data) => data.borrow::<WaitUntil>().map(|u| u.clone()).unwrap_or(WaitUntil::Ticks(1)),
+        _ => WaitUntil::Ticks(1),
+    };
+    let thread = lua.create_registry_value(thread)?;
+    if let Some(mut waiting) = lua.app_data_mut::<WaitingThreads>() {
+        waiting.0.push(Waiting { thread, entity, until, reply, source });
+    }
+    Ok(())
+}
+
+/// Resumes every coroutine whose wait `is_over`, passing it `args`
+///
+/// Errors thrown by the coroutines, or from fetching them, are logged, since the others still need to run
+pub fn resume_waiting<'lua, A>(lua: &'lua Lua, mut is_over: impl FnMut(&mut WaitUntil) -> bool, args: A) -> Result<(), LuaError>
+where A: ToLuaMulti<'lua> + Clone {
+    let waiting = match lua.app_data_mut::<WaitingThreads>() {
+        Some(mut waiting) => std::mem::take(&mut waiting.0),
+        None => return Ok(()),
+    };
+    let mut still_waiting = Vec::new();
+    for mut w in waiting {
+        if !is_over(&mut w.until) {
+            still_waiting.push(w);
+            continue;
+        }
+        let thread = lua.registry_value::<LuaThread>(&w.thread);
+        let removed = lua.remove_registry_value(w.thread);
+        let thread = match thread.and_then(|thread| removed.map(|_| thread)) {
+            Ok(thread) => thread,
+            Err(err) => {
+                report_error(lua, "coroutine", w.entity, &err);
+                // a coroutine that's lost won't reply, so whoever waits on it is answered with nothing
+                if let Some(reply) = w.reply && let Err(err) = send_reply(lua, reply, None) {
+                    report_error(lua, "coroutine", w.entity, &err);
+                }
+                continue;
+            },
+        };
+        let previous = lua.globals().get::<_, LuaValue>("entity").unwrap_or(LuaValue::Nil);
+        if let Some(entity) = w.entity {
+            let _ = lua.globals().set("entity", LuaEntity(entity));
+        }
+        let resumed = profiled_in(lua, w.source.as_deref(), "coroutine", w.entity, || budgeted(lua, || thread.resume::<_, LuaMultiValue>(args.clone())));
+        if let Err(err) = finish_or_park(lua, thread, resumed, w.entity, w.reply, w.source) {
+            report_error(lua, "coroutine", w.entity, &err);
+        }
+        let _ = lua.globals().set("entity", previous);
+    }
+    // coroutines that waited again while resuming go after the ones that were already waiting
+    if let Some(mut waiting) = lua.app_data_mut::<WaitingThreads>() {
+        let parked = std::mem::replace(&mut waiting.0, still_waiting);
+        waiting.0.extend(parked);
+    }
+    Ok(())
+}
+
+/// Drops every coroutine started for this entity, so they don't resume after it's gone
+pub fn forget_entity(lua: &Lua, entity: Entity) -> Result<(), LuaError> {
+    let forgotten: Vec<Waiting> = match lua.app_data_mut::<WaitingThreads>() {
+        Some(mut waiting) => {
+            let (forgotten, kept) = std::mem::take(&mut waiting.0).into_iter().partition(|w| w.entity == Some(entity));
+            waiting.0 = kept;
+            forgotten
+        },
+        None => return Ok(()),
+    };
+    for w in forgotten {
+        lua.remove_registry_value(w.thread)?;
+        if let Some(reply) = w.reply {
+            send_reply(lua, reply, None)?;
+        }
+    }
+    Ok(())
+}
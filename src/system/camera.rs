@@ -12,7 +12,9 @@ pub struct CameraPlugin;
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app
+            .register_type::<ActiveCamera>()
             .register_type::<Focus>()
+            .register_type::<Option<Entity>>()
             .add_system(setup_camera)
             .add_system(cam_movement.run_if_not(ui::is_ui_focused))
             // .add_system(follow_system)
@@ -33,6 +35,7 @@ impl Default for Focus {
 }
 
 #[derive(Clone, Component, Debug, Deserialize, PartialEq, Reflect, Serialize)]
+#[reflect(Component)]
 pub struct ActiveCamera {
     pub controller: Option<Entity>,
     pub focus: Focus,
@@ -0,0 +1,332 @@
+use std::{collections::hash_map::DefaultHasher, fs, hash::{Hash, Hasher}, path::Path, time::Duration};
+
+use bevy::{prelude::*, diagnostic::{Diagnostic, DiagnosticId, Diagnostics}};
+use bevy_egui::{egui, EguiContext};
+
+use crate::{data::assetio::VirtualAssetIo, scripting::diagnostics::{ProfileKey, ProfileStats, ScriptDiagnostics, ScriptError, ScriptProfiler}};
+
+#[derive(Clone, Debug, Default)]
+pub struct ScriptDiagnosticsPlugin;
+
+impl Plugin for ScriptDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<ScriptConsole>()
+            .init_resource::<ProfilerOverlay>()
+            .add_startup_system(setup_script_diagnostics)
+            .add_system(show_script_console)
+            .add_system(show_profiler_overlay)
+            .add_system_to_stage(CoreStage::Last, measure_script_time)
+        ;
+    }
+}
+
+/// Opens and closes the script error console
+pub const SCRIPT_CONSOLE_KEY: KeyCode = KeyCode::F6;
+
+#[derive(Debug, Default, Resource)]
+pub struct ScriptConsole {
+    pub open:          bool,
+    pub mod_filter:    Option<String>,
+    pub script_filter: Option<String>,
+    /// The file and line being shown, and the file's source or why it couldn't be read
+    pub source:        Option<(String, usize, Result<String, String>)>,
+    scroll_to_line:    bool,
+}
+impl ScriptConsole {
+    fn shows(&self, error: &ScriptError) -> bool {
+        self.mod_filter.as_ref().map_or(true, |m| *m == error.owner)
+            && self.script_filter.as_ref().map_or(true, |s| *s == error.script)
+    }
+}
+
+/// Reads a file an error points at, which is either an asset path or a path `require` found on disk
+fn read_source(asset_server: &AssetServer, file: &str) -> Result<String, String> {
+    let from_assets = asset_server.asset_io().downcast_ref::<VirtualAssetIo>()
+        .and_then(|io| io.resolve(Path::new(file)).read().ok());
+    match from_assets {
+        Some(bytes) => Ok(String::from_utf8_lossy(&bytes).to_string()),
+        None => fs::read(file)
+            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+            .map_err(|e| format!("Unable to read {}: {}", file, e)),
+    }
+}
+
+fn filter_combo<'a>(ui: &mut egui::Ui, label: &str, filter: &mut Option<String>, options: impl Iterator<Item = &'a String>) {
+    let mut options: Vec<&String> = options.collect();
+    options.sort();
+    options.dedup();
+    egui::ComboBox::from_label(label)
+        .selected_text(filter.clone().unwrap_or_else(|| "All".to_string()))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(filter, None, "All");
+            for option in options {
+                ui.selectable_value(filter, Some(option.clone()), option.as_str());
+            }
+        });
+}
+
+pub fn show_script_console(
+    keys:         Res<Input<KeyCode>>,
+    asset_server: Res<AssetServer>,
+    diagnostics:  Res<ScriptDiagnostics>,
+    mut console:  ResMut<ScriptConsole>,
+    mut egui_ctx: ResMut<EguiContext>,
+) {
+    if keys.just_pressed(SCRIPT_CONSOLE_KEY) {
+        console.open = !console.open;
+    }
+    if !console.open {
+        return;
+    }
+    let console = &mut *console;
+    let mut open = true;
+    let mut clear = false;
+    let mut goto = None;
+    {
+        let errors = diagnostics.errors();
+        egui::Window::new(format!("Script errors ({})", errors.iter().map(|e| e.count).sum::<u32>()))
+            .id(egui::Id::new("script_console"))
+            .open(&mut open)
+            .default_width(640.)
+            .show(egui_ctx.ctx_mut(), |ui| {
+                ui.horizontal(|ui| {
+                    filter_combo(ui, "Mod", &mut console.mod_filter, errors.iter().map(|e| &e.owner));
+                    let mod_filter = console.mod_filter.clone();
+                    filter_combo(ui, "Script", &mut console.script_filter, errors.iter()
+                        .filter(|e| mod_filter.as_ref().map_or(true, |m| *m == e.owner))
+                        .map(|e| &e.script));
+                    if ui.button("Clear").clicked() {
+                        clear = true;
+                    }
+                });
+                ui.separator();
+                egui::ScrollArea::vertical().id_source("script_errors").max_height(300.).show(ui, |ui| {
+                    for (i, error) in errors.iter().enumerate().filter(|(_, e)| console.shows(e)) {
+                        ui.horizontal(|ui| {
+                            ui.label(egui::RichText::new(format!("×{}", error.count)).strong());
+                            ui.label(format!("{} ({}) in {}", error.script, error.owner, error.hook));
+                            if let Some(entity) = error.entity {
+                                ui.label(format!("{:?}", entity));
+                            }
+                            if let Some((file, line)) = &error.location {
+                                if ui.link(format!("{}:{}", file, line)).clicked() {
+                                    goto = Some((file.clone(), *line));
+                                }
+                            }
+                        });
+                        ui.label(egui::RichText::new(&error.message).color(egui::Color32::LIGHT_RED));
+                        if let Some(traceback) = &error.traceback {
+                            egui::CollapsingHeader::new("Traceback").id_source(i).show(ui, |ui| {
+                                ui.label(egui::RichText::new(traceback).monospace());
+                            });
+                        }
+                        ui.separator();
+                    }
+                });
+
+                let mut close_source = false;
+                if let Some((file, line, source)) = &console.source {
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}:{}", file, line));
+                        close_source = ui.button("Close").clicked();
+                    });
+                    egui::ScrollArea::vertical().id_source("script_source").max_height(300.).show(ui, |ui| {
+                        match source {
+                            Ok(source) => for (n, text) in source.lines().enumerate().map(|(n, t)| (n + 1, t)) {
+                                let text = egui::RichText::new(format!("{:>4}  {}", n, text)).monospace();
+                                if n == *line {
+                                    let response = ui.label(text.background_color(egui::Color32::DARK_RED));
+                                    if console.scroll_to_line {
+                                        response.scroll_to_me(Some(egui::Align::Center));
+                                    }
+                                } else {
+                                    ui.label(text);
+                                }
+                            },
+                            Err(e) => { ui.label(e); },
+                        }
+                    });
+                    console.scroll_to_line = false;
+                }
+                if close_source {
+                    console.source = None;
+                }
+            });
+    }
+
+    if let Some((file, line)) = goto {
+        let source = read_source(&asset_server, &file);
+        console.source = Some((file, line, source));
+        console.scroll_to_line = true;
+    }
+    if clear {
+        diagnostics.clear();
+        console.source = None;
+    }
+    console.open = open;
+}
+
+/// Opens and closes the script profiler
+pub const PROFILER_KEY: KeyCode = KeyCode::F7;
+
+/// Time spent in every script during a frame, in milliseconds
+pub const SCRIPT_TIME: DiagnosticId = DiagnosticId::from_u128(0x5c61_7074_5f74_696d_6500_0000_0000_0000);
+/// How many frames of history each script's diagnostic keeps
+const SCRIPT_TIME_HISTORY: usize = 20;
+
+/// The diagnostic of a single script's time per frame, which is made from its path so it stays the same across reloads
+pub fn script_time_id(script: &str) -> DiagnosticId {
+    let mut hasher = DefaultHasher::new();
+    script.hash(&mut hasher);
+    DiagnosticId::from_u128(SCRIPT_TIME.0.as_u128() ^ hasher.finish() as u128)
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.
+}
+
+pub fn setup_script_diagnostics(mut diagnostics: ResMut<Diagnostics>) {
+    diagnostics.add(Diagnostic::new(SCRIPT_TIME, "script_time", SCRIPT_TIME_HISTORY).with_suffix("ms"));
+}
+
+/// Ends the profiler's frame, and adds the time each script spent during it to Bevy's diagnostics
+pub fn measure_script_time(
+    profiler:        Res<ScriptProfiler>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    let per_script = profiler.end_frame();
+    diagnostics.add_measurement(SCRIPT_TIME, || as_ms(per_script.values().sum()));
+    for (script, time) in per_script {
+        let id = script_time_id(&script);
+        if diagnostics.get(id).is_none() {
+            diagnostics.add(Diagnostic::new(id, format!("script_time {}", script), SCRIPT_TIME_HISTORY).with_suffix("ms"));
+        }
+        diagnostics.add_measurement(id, || as_ms(time));
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProfileColumn {
+    Script,
+    Hook,
+    Entity,
+    Calls,
+    LastFrame,
+    Average,
+    Max,
+    Total,
+}
+impl ProfileColumn {
+    const ALL: [ProfileColumn; 8] = [
+        ProfileColumn::Script, ProfileColumn::Hook, ProfileColumn::Entity, ProfileColumn::Calls,
+        ProfileColumn::LastFrame, ProfileColumn::Average, ProfileColumn::Max, ProfileColumn::Total,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ProfileColumn::Script    => "Script",
+            ProfileColumn::Hook      => "Hook",
+            ProfileColumn::Entity    => "Entity",
+            ProfileColumn::Calls     => "Calls",
+            ProfileColumn::LastFrame => "Last frame (ms)",
+            ProfileColumn::Average   => "Average (ms)",
+            ProfileColumn::Max       => "Max (ms)",
+            ProfileColumn::Total     => "Total (ms)",
+        }
+    }
+
+    fn cmp(&self, (a_key, a): &(ProfileKey, ProfileStats), (b_key, b): &(ProfileKey, ProfileStats)) -> std::cmp::Ordering {
+        match self {
+            ProfileColumn::Script    => a_key.script.cmp(&b_key.script),
+            ProfileColumn::Hook      => a_key.hook.cmp(&b_key.hook),
+            ProfileColumn::Entity    => a_key.entity.cmp(&b_key.entity),
+            ProfileColumn::Calls     => a.calls.cmp(&b.calls),
+            ProfileColumn::LastFrame => a.last_frame.cmp(&b.last_frame),
+            ProfileColumn::Average   => a.average().cmp(&b.average()),
+            ProfileColumn::Max       => a.max.cmp(&b.max),
+            ProfileColumn::Total     => a.total.cmp(&b.total),
+        }
+    }
+}
+
+#[derive(Debug, Resource)]
+pub struct ProfilerOverlay {
+    pub open:       bool,
+    pub sort_by:    ProfileColumn,
+    pub descending: bool,
+}
+impl Default for ProfilerOverlay {
+    fn default() -> Self {
+        Self { open: false, sort_by: ProfileColumn::Total, descending: true }
+    }
+}
+
+pub fn show_profiler_overlay(
+    keys:         Res<Input<KeyCode>>,
+    profiler:     Res<ScriptProfiler>,
+    mut overlay:  ResMut<ProfilerOverlay>,
+    mut egui_ctx: ResMut<EguiContext>,
+) {
+    if keys.just_pressed(PROFILER_KEY) {
+        overlay.open = !overlay.open;
+    }
+    if !overlay.open {
+        return;
+    }
+    let overlay = &mut *overlay;
+    let mut rows: Vec<(ProfileKey, ProfileStats)> = profiler.stats().iter().map(|(key, stats)| (key.clone(), *stats)).collect();
+    rows.sort_by(|a, b| {
+        let ordering = overlay.sort_by.cmp(a, b);
+        if overlay.descending { ordering.reverse() } else { ordering }
+    });
+    let mut open = true;
+    let mut reset = false;
+    egui::Window::new("Script profiler")
+        .open(&mut open)
+        .default_width(720.)
+        .show(egui_ctx.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                let last_frame: Duration = rows.iter().map(|(_, stats)| stats.last_frame).sum();
+                ui.label(format!("{:.3}ms in scripts last frame", as_ms(last_frame)));
+                reset = ui.button("Reset").clicked();
+            });
+            ui.separator();
+            egui::ScrollArea::both().max_height(400.).show(ui, |ui| {
+                egui::Grid::new("script_profile").striped(true).show(ui, |ui| {
+                    for column in ProfileColumn::ALL {
+                        let arrow = match (overlay.sort_by == column, overlay.descending) {
+                            (true, true)  => " ⏷",
+                            (true, false) => " ⏶",
+                            _             => "",
+                        };
+                        if ui.button(format!("{}{}", column.name(), arrow)).clicked() {
+                            if overlay.sort_by == column {
+                                overlay.descending = !overlay.descending;
+                            } else {
+                                overlay.sort_by = column;
+                                overlay.descending = true;
+                            }
+                        }
+                    }
+                    ui.end_row();
+                    for (key, stats) in rows.iter() {
+                        ui.label(&key.script);
+                        ui.label(&key.hook);
+                        ui.label(key.entity.map_or(String::new(), |e| format!("{:?}", e)));
+                        ui.label(stats.calls.to_string());
+                        ui.label(format!("{:.3}", as_ms(stats.last_frame)));
+                        ui.label(format!("{:.3}", as_ms(stats.average())));
+                        ui.label(format!("{:.3}", as_ms(stats.max)));
+                        ui.label(format!("{:.3}", as_ms(stats.total)));
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+    if reset {
+        profiler.reset();
+    }
+    overlay.open = open;
+}
@@ -3,7 +3,7 @@ use std::{collections::{HashMap, HashSet}};
 use bevy::{prelude::*};
 use indexmap::IndexMap;
 
-use crate::{data::{level::*, material::{TextureMaterial, AtlasIndex, TexMatInfo, MaterialColors, MaterialsToInit, LoadedMat}, geometry::{Shape, LightAnimState, LightAnim}, prefab::{PrefabLoader, Prefab}, lua::{LuaScript, Hook, ManyTransVars, TransVar, LuaTransVars}}, scripting::{event::{ON_ROOM_REVEAL, EventFlag}}};
+use crate::{data::{level::*, material::{TextureMaterial, AtlasIndex, TexMatInfo, MaterialColors, MaterialsToInit, LoadedMat}, geometry::{Shape, LightAnimState, LightAnim}, prefab::{PrefabLoader, Prefab}, lua::{LuaScript, Hook, ManyTransVars, TransVar, LuaTransVars}}, scripting::{event::ON_ROOM_REVEAL}};
 
 use super::{texture::{MissingTexture, Background}, common::{fix_missing_extension, ToInitHandle}, lua::{ToInitScripts, SharedInstances, LuaQueue, HookCall, LuaEventQueue, EventCall}};
 
@@ -226,11 +226,11 @@ pub fn spawn_room(
                         name: ON_ROOM_REVEAL.into(),
                         args: args.clone(),
                     },
+                    reply_to: None,
                 }]
             });
             event_queue.calls.push(
                 EventCall {
-                    flag: EventFlag::ON_ROOM_REVEAL,
                     hook: Hook {
                         name: ON_ROOM_REVEAL.into(),
                         args,
@@ -1,4 +1,5 @@
-use std::time::Duration;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 use std::{collections::{HashMap, HashSet}};
 
 use bevy::asset::LoadState;
@@ -11,14 +12,22 @@ use mlua::prelude::*;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 
+use crate::data::assetio::{VirtualAssetIo, VirtualFileOverrides};
 use crate::data::level::{LoadedLevel, LoadedLevelCache};
 use crate::data::lua::{LuaScript, LuaScriptLoader, InstanceKind, InstanceRef, Hook, LuaWorld, ScriptVar};
 use crate::scripting::bevy_api::LuaEntity;
 use crate::scripting::bevy_api::handle::{LuaAssetEventRegistry, AssetEventKey, LuaHandle, AssetKind};
-use crate::scripting::event::{constants, ON_UPDATE, ON_INIT, EventFlag, ON_ROOM_REVEAL};
+use crate::scripting::message::{DelayedMessage, LuaMessages, ReplyTo, call_reply, forget_reply};
+use crate::scripting::event::{constants, ON_UPDATE, ON_INIT, ON_DROP, ON_RELOAD, SAVE_STATE, EventSubscribers, script_hooks};
+use crate::scripting::permission::ScriptPermissions;
+use crate::scripting::diagnostics::{ScriptDiagnostics, ScriptPath, ScriptProfiler, SharedScriptOwners, profiled, profiled_call, report_error};
 use crate::scripting::register_lua_mods;
-use crate::scripting::time::LuaTime;
+use crate::scripting::sandbox::{budgeted, sandbox};
+use crate::scripting::wait::{forget_entity, resume_waiting};
+use crate::scripting::time::{LuaTime, fire_timers, forget_entity_timers};
 use crate::scripting::ui::atom::LuaAtomRegistry;
+use crate::scripting::ui::elem::{Container, VisibleContainers};
+use crate::system::module::LoadedModList;
 
 #[derive(Clone, Debug, Default)]
 pub struct LuaPlugin;
@@ -28,6 +37,7 @@ impl Plugin for LuaPlugin {
         let mut on_update = SystemStage::single_threaded();
         on_update
             .add_system(update_script_event_queue)
+            .add_system(resume_lua_threads)
             .add_system(on_asset_load);
 
         let mut on_queue_collect = SystemStage::parallel();
@@ -36,12 +46,22 @@ impl Plugin for LuaPlugin {
         app
             .init_resource::<LuaAtomRegistry>()
             .init_resource::<LuaEventQueue>()
+            .init_resource::<LuaMessages>()
+            .init_resource::<ScriptDiagnostics>()
+            .init_resource::<ScriptProfiler>()
             .init_resource::<LuaTime>()
             .init_resource::<LuaAssetEventRegistry>()
             .init_resource::<SharedInstances>()
+            .init_resource::<LuaScriptWatcher>()
             .add_asset::<LuaScript>()
+            .add_event::<DetachScripts>()
             .init_asset_loader::<LuaScriptLoader>()
             .add_system(init_lua_script)
+            .add_system(fire_lua_timers)
+            .add_system(deliver_lua_messages)
+            .add_system(watch_lua_scripts)
+            .add_system(reload_lua_script)
+            .add_system_to_stage(CoreStage::Last, drop_lua_scripts)
             .add_stage_before(
                 CoreStage::Update,
                 "lua_events",
@@ -63,13 +83,15 @@ impl Plugin for LuaPlugin {
 pub struct HookCall {
     pub script_ids: HashSet<u32>,
     pub hook:       Hook,
+    /// Where to send what the hook returns, if it's answering a message
+    pub reply_to:   Option<ReplyTo>,
 }
 impl HookCall {
-    pub fn next_frame(hook: Hook) -> Self { HookCall { script_ids: HashSet::new(), hook }}
+    pub fn next_frame(hook: Hook) -> Self { HookCall { script_ids: HashSet::new(), hook, reply_to: None }}
 }
+/// A hook called on every script that defines it, on each entity running the script
 #[derive(Clone, Debug)]
 pub struct EventCall {
-    pub flag: EventFlag,
     pub hook: Hook,
 }
 
@@ -100,14 +122,23 @@ impl LuaUserData for ScriptRefs {
     }
 }
 
+/// Sent when scripts are detached from an entity that stays alive, so on_drop is called for them before they're released
+#[derive(Clone, Debug)]
+pub struct DetachScripts {
+    pub entity: Entity,
+    pub ids:    HashSet<u32>,
+}
+
 #[derive(Resource)]
 pub struct SharedInstances {
     pub next_id:      u32,
     pub collectivist: InstanceRef,
     pub by_path:      HashMap<String, HashMap<Entity, u32>>,
+    /// The ids in each entity's [`ScriptRefs`], kept here since the component is gone by the time the entity is dropped
+    pub by_entity:    HashMap<Entity, HashSet<u32>>,
     pub instances:    HashMap<u32, LuaInstance>,
     pub shared:       HashMap<Handle<LuaScript>, u32>,
-    pub event_flags:  HashMap<u32, EventFlag>,
+    pub subscribers:  EventSubscribers,
 }
 impl SharedInstances {
     pub const COLLECTIVIST_ID: u32 = 0;
@@ -117,8 +148,84 @@ impl SharedInstances {
         self.next_id - 1
     }
 
-    pub fn has_event_flags(&self, flags: EventFlag, script_id: u32) -> bool {
-        self.event_flags.get(&script_id).map(|i| *i).unwrap_or(EventFlag::empty()).contains(flags)
+    /// The instance a script recorded under `id` runs in, since collectivist scripts are recorded by their own id but run in the collectivist's
+    pub fn instance_of(&self, id: u32) -> u32 {
+        if self.instances.contains_key(&id) { id } else { Self::COLLECTIVIST_ID }
+    }
+
+    /// Forgets these instances and everything recorded about them, once no entity runs them anymore
+    pub fn drop_instances(&mut self, ids: &HashSet<u32>) {
+        self.instances.retain(|id, _| !ids.contains(id));
+        for id in ids {
+            self.subscribers.remove(*id);
+        }
+        self.shared.retain(|_, id| !ids.contains(id));
+        self.by_path.retain(|_, entities| {
+            entities.retain(|_, id| !ids.contains(id));
+            !entities.is_empty()
+        });
+    }
+
+    /// Forgets that this entity runs these instances, then drops the ones no other entity runs anymore
+    /// 
+    /// The collectivist is never dropped, since any entity can start running it again. Returns the ids that were dropped
+    pub fn release(&mut self, entity: Entity, ids: &HashSet<u32>) -> HashSet<u32> {
+        if let Some(refs) = self.by_entity.get_mut(&entity) {
+            refs.retain(|id| !ids.contains(id));
+            if refs.is_empty() {
+                self.by_entity.remove(&entity);
+            }
+        }
+        for id in ids {
+            self.subscribers.detach(entity, *id);
+        }
+        let mut by_path = std::mem::take(&mut self.by_path);
+        by_path.retain(|_, entities| {
+            entities.retain(|e, id| *e != entity || !(ids.contains(id) || ids.contains(&self.instance_of(*id))));
+            !entities.is_empty()
+        });
+        self.by_path = by_path;
+
+        let in_use: HashSet<u32> = self.by_entity.values().flatten().copied().collect();
+        let dropped = ids.iter()
+            .filter(|id| **id != Self::COLLECTIVIST_ID && !in_use.contains(id))
+            .copied()
+            .collect();
+        self.drop_instances(&dropped);
+        dropped
+    }
+
+    /// Forgets that the script loaded from `path` runs on this entity, returning the id its [`ScriptRefs`] should lose
+    /// 
+    /// Collectivist scripts all run under [`Self::COLLECTIVIST_ID`], so it's only returned once the entity has none of them left
+    pub fn detach(&mut self, entity: Entity, path: &str) -> Option<u32> {
+        let entities = self.by_path.get_mut(path)?;
+        let id = entities.remove(&entity)?;
+        if entities.is_empty() {
+            self.by_path.remove(path);
+        }
+        if self.instance_of(id) == id {
+            Some(id)
+        } else if self.by_path.values().any(|entities| entities.get(&entity).map_or(false, |id| self.instance_of(*id) == Self::COLLECTIVIST_ID)) {
+            None
+        } else {
+            Some(Self::COLLECTIVIST_ID)
+        }
+    }
+
+    /// Every instance that loaded without errors, including the collectivist
+    pub fn loaded(&self) -> impl Iterator<Item = (u32, &InstanceRef)> {
+        self.instances.iter()
+            .filter_map(|(id, inst)| inst.result.as_ref().ok().map(|r| (*id, r)))
+            .chain(std::iter::once((Self::COLLECTIVIST_ID, &self.collectivist)))
+    }
+
+    /// The instance with this id, if it loaded without errors
+    pub fn get_loaded(&self, id: u32) -> Option<&InstanceRef> {
+        match id {
+            Self::COLLECTIVIST_ID => Some(&self.collectivist),
+            id => self.instances.get(&id).and_then(|i| i.result.as_ref().ok()),
+        }
     }
 }
 impl Default for SharedInstances {
@@ -133,7 +240,23 @@ impl Default for SharedInstances {
             shared: HashMap::new(),
             instances: HashMap::new(),
             by_path: HashMap::new(),
-            event_flags: HashMap::new(),
+            by_entity: HashMap::new(),
+            subscribers: EventSubscribers::default(),
+        }
+    }
+}
+
+/// When each loaded script's file was last modified, to reload the ones that get edited
+#[derive(Resource)]
+pub struct LuaScriptWatcher {
+    pub timer:    Timer,
+    pub modified: HashMap<PathBuf, SystemTime>,
+}
+impl Default for LuaScriptWatcher {
+    fn default() -> Self {
+        LuaScriptWatcher {
+            timer:    Timer::from_seconds(constants::SCRIPT_WATCH_DELAY, TimerMode::Repeating),
+            modified: HashMap::new(),
         }
     }
 }
@@ -143,17 +266,49 @@ pub struct ToInitScripts {
     pub handles: IndexMap<u32, Handle<LuaScript>>,
 }
 
-pub fn load_script_on_lua(lua: &Lua, script: &LuaScript, world: LuaWorld, id: u32) -> Result<(), LuaError> {
-    lua.globals().set("world", world)?;
-    register_lua_mods(&lua)?;
-    lua.globals().set("script_id", id)?;
-    lua.load(&script.source).exec()?;
-    Ok(())
+pub fn load_script_on_lua(lua: &Lua, script: &LuaScript, path: &str, world: LuaWorld, id: u32, permissions: &ScriptPermissions) -> Result<(), LuaError> {
+    let diagnostics = world.read().resource::<ScriptDiagnostics>().clone();
+    lua.set_app_data(ScriptPath(path.to_string()));
+    lua.set_app_data(diagnostics.clone());
+    lua.set_app_data(world.read().resource::<ScriptProfiler>().clone());
+    (|| {
+        sandbox(lua)?;
+        lua.globals().set("world", world)?;
+        register_lua_mods(&lua, permissions)?;
+        lua.globals().set("script_id", id)?;
+        profiled(lua, "load", None, || budgeted(lua, || lua.load(&script.source).set_name(&chunk_name(path))?.exec()))
+    })().map_err(|err| {
+        let owner = lua.app_data_ref::<SharedScriptOwners>()
+            .and_then(|owners| owners.0.get(path).cloned())
+            .unwrap_or_else(|| permissions.owner.clone());
+        diagnostics.record(path, &owner, "load", None, &err);
+        err
+    })
+}
+
+/// Makes Lua point at the script's path in errors and tracebacks, rather than a snippet of its source
+pub fn chunk_name(path: &str) -> String {
+    format!("@{}", path)
+}
+
+/// Runs a script's new source in its existing scope, handing whatever save_state returns to on_reload
+pub fn reload_script_on_lua(lua: &Lua, script: &LuaScript, path: &str) -> Result<(), LuaError> {
+    budgeted(lua, || {
+        let old_state = match lua.globals().get::<_, Option<LuaFunction>>(SAVE_STATE)? {
+            Some(f) => f.call::<_, LuaValue>(())?,
+            None    => LuaValue::Nil,
+        };
+        lua.load(&script.source).set_name(&chunk_name(path))?.exec()?;
+        if let Some(f) = lua.globals().get::<_, Option<LuaFunction>>(ON_RELOAD)? {
+            f.call::<_, ()>(old_state)?;
+        }
+        Ok(())
+    })
 }
 
-pub fn load_script(script: &LuaScript, world: LuaWorld, id: u32) -> Result<InstanceRef, LuaError> {
+pub fn load_script(script: &LuaScript, path: &str, world: LuaWorld, id: u32, permissions: &ScriptPermissions) -> Result<InstanceRef, LuaError> {
     let lua = Lua::new();
-    load_script_on_lua(&lua, script, world, id)?;
+    load_script_on_lua(&lua, script, path, world, id, permissions)?;
     Ok(RwLock::new(lua).into())
 }
 
@@ -164,12 +319,14 @@ pub fn init_lua_script(
         Res<AssetServer>,
         ResMut<SharedInstances>,
         Res<Assets<LuaScript>>,
+        Res<LoadedModList>,
+        Res<VirtualFileOverrides>,
         Local<bool>,
         Query<(Entity, &mut ToInitScripts, Option<&mut ScriptRefs>, Option<&mut LuaQueue>)>,
     )>,
 ) {
     let lua_world = unsafe { LuaWorld::new(world) };
-    let (mut commands, asset_server, mut instances, lua_scripts, mut is_collectivist_empty, mut query) = state.get_mut(world);
+    let (mut commands, asset_server, mut instances, lua_scripts, loaded_ml, file_overrides, mut is_collectivist_empty, mut query) = state.get_mut(world);
     'query: for (entity, to_init, script_refs, lua_queue) in query.iter_mut() {
         let mut scripts = IndexMap::new();
         for (id, handle) in to_init.handles.iter() {
@@ -202,10 +359,8 @@ pub fn init_lua_script(
                     instances.by_path.entry(path.clone())
                         .or_insert_with(|| HashMap::new())
                         .insert(entity, id);
-                    let result = load_script(&script, lua_world.clone(), id);
-                    if let Err(err) = &result {
-                        error!("Failed to load {}: {}", path, err);
-                    }
+                    let permissions = loaded_ml.script_permissions(&path, &file_overrides);
+                    let result = load_script(&script, &path, lua_world.clone(), id, &permissions);
                     instances.instances.insert(id, LuaInstance {
                         handle: handle.clone_weak(),
                         path,
@@ -216,6 +371,10 @@ pub fn init_lua_script(
                     match instances.shared.get(&handle) {
                         Some(instance_id) => {
                             ids.insert(*instance_id);
+                            let path = get_path(&asset_server, handle);
+                            instances.by_path.entry(path)
+                                .or_insert_with(|| HashMap::new())
+                                .insert(entity, *instance_id);
                         },
                         None => {
                             let path = get_path(&asset_server, handle);
@@ -223,10 +382,8 @@ pub fn init_lua_script(
                             instances.by_path.entry(path.clone())
                                 .or_insert_with(|| HashMap::new())
                                 .insert(entity, id);
-                            let result = load_script(&script, lua_world.clone(), id);
-                            if let Err(err) = &result {
-                                error!("Failed to load {}: {}", path, err);
-                            }
+                            let permissions = loaded_ml.script_permissions(&path, &file_overrides);
+                            let result = load_script(&script, &path, lua_world.clone(), id, &permissions);
                             instances.instances.insert(id, LuaInstance {
                                 handle: handle.clone_weak(),
                                 path,
@@ -244,15 +401,23 @@ pub fn init_lua_script(
                         .insert(entity, id);
                     {
                         let w = instances.collectivist.lock.write();
+                        // errors and time in the collectivist are recorded under the script that threw or ran, and the mod that owns it
+                        let owner = loaded_ml.script_permissions(&path, &file_overrides).owner;
+                        match w.app_data_mut::<SharedScriptOwners>() {
+                            Some(mut owners) => { owners.0.insert(path.clone(), owner); },
+                            None => { w.set_app_data(SharedScriptOwners(HashMap::from([(path.clone(), owner)]))); },
+                        }
                         if *is_collectivist_empty {
-                            let _ = load_script_on_lua(&w, script, lua_world.clone(), SharedInstances::COLLECTIVIST_ID).map_err(|err| {
-                                error!("Failed to load {}: {}", path, err);
-                            });
+                            // the collectivist scope is shared by every mod, so it can't be trusted with any of their permissions
+                            let permissions = ScriptPermissions::new("collectivist".to_string(), HashSet::new());
+                            let _ = load_script_on_lua(&w, script, &path, lua_world.clone(), SharedInstances::COLLECTIVIST_ID, &permissions);
+                            // anything that can't be traced back to one of its scripts is recorded under the collectivist itself
+                            w.set_app_data(ScriptPath("collectivist".to_string()));
 
                             *is_collectivist_empty = false;
                         } else {
-                            let _ = w.load(&script.source).exec().map_err(|err| {
-                                error!("Failed to load {}: {}", path, err);
+                            let _ = budgeted(&w, || w.load(&script.source).set_name(&chunk_name(&path))?.exec()).map_err(|err| {
+                                report_error(&w, "load", Some(entity), &err);
                             });
                         }
                     }
@@ -261,6 +426,12 @@ pub fn init_lua_script(
         }
         commands.entity(entity)
             .remove::<ToInitScripts>();
+        instances.by_entity.entry(entity)
+            .or_insert_with(|| HashSet::new())
+            .extend(ids.iter().copied());
+        for id in ids.iter() {
+            instances.subscribers.attach(entity, *id);
+        }
         if let Some(mut script_refs) = script_refs {
             script_refs.ids.extend(ids);
         } else {
@@ -270,92 +441,314 @@ pub fn init_lua_script(
     state.apply(world);
 }
 
+/// Calls on_drop for scripts that were detached or whose entity was despawned, then releases their instances
+pub fn drop_lua_scripts(
+    mut si:           ResMut<SharedInstances>,
+    mut registry:     ResMut<LuaAssetEventRegistry>,
+    mut visibilities: ResMut<VisibleContainers>,
+    containers:       Res<Assets<Container>>,
+    mut detached:     EventReader<DetachScripts>,
+    removed:          RemovedComponents<ScriptRefs>,
+    removed_queues:   RemovedComponents<LuaQueue>,
+    mut messages:     ResMut<LuaMessages>,
+    profiler:         Res<ScriptProfiler>,
+) {
+    let mut drop_scripts = |si: &mut SharedInstances, entity: Entity, ids: HashSet<u32>| {
+        let hook = Hook { name: ON_DROP.to_string(), args: default() };
+        for id in ids.iter() {
+            if let Some(inst_ref) = si.get_loaded(*id) {
+                let _ = hook.exec(&inst_ref.lock, entity.into()).map_err(|e| {
+                    hook.log_err(&inst_ref.lock.read(), entity.into(), e);
+                });
+                let lua = inst_ref.lock.write();
+                let _ = forget_entity(&lua, entity).and_then(|_| forget_entity_timers(&lua, entity)).map_err(|e| {
+                    error!("Failed to drop coroutines and timers of {:?} in script id #{:?}: {}", entity, id, e);
+                });
+            }
+        }
+        let dropped = si.release(entity, &ids);
+        if !si.by_entity.contains_key(&entity) {
+            profiler.forget_entity(entity);
+        }
+        if !dropped.is_empty() {
+            registry.on_asset_load.retain(|key, _| !dropped.contains(&key.script_id));
+            visibilities.0.retain(|id| containers.get(*id).map(|c| !dropped.contains(&c.script_id)).unwrap_or(true));
+        }
+    };
+
+    for DetachScripts { entity, ids } in detached.iter() {
+        drop_scripts(&mut si, *entity, ids.clone());
+    }
+    for entity in removed.iter() {
+        if let Some(ids) = si.by_entity.get(&entity).cloned() {
+            drop_scripts(&mut si, entity, ids);
+        }
+    }
+    // calls still queued on a despawned entity never run, so the messages they were for can't wait on them
+    for entity in removed_queues.iter() {
+        messages.drop_queued(entity);
+    }
+}
+
+/// Reloads scripts whose files were modified since they were loaded
+pub fn watch_lua_scripts(
+    time:         Res<Time>,
+    asset_server: Res<AssetServer>,
+    lua_scripts:  Res<Assets<LuaScript>>,
+    mut watcher:  ResMut<LuaScriptWatcher>,
+) {
+    if !watcher.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let Some(asset_io) = asset_server.asset_io().downcast_ref::<VirtualAssetIo>() else {
+        return;
+    };
+    for (id, _) in lua_scripts.iter() {
+        let Some(asset_path) = asset_server.get_handle_path(id) else {
+            continue;
+        };
+        let path = asset_path.path().to_path_buf();
+        let Ok(modified) = asset_io.resolve(&path).modified() else {
+            continue;
+        };
+        if let Some(prev) = watcher.modified.insert(path.clone(), modified) && prev != modified {
+            info!("{} changed, reloading it", path.to_string_lossy());
+            asset_server.reload_asset(path.as_path());
+        }
+    }
+}
+
+/// Runs the new source of reloaded scripts in every instance already running them
+/// 
+/// Instances that failed to load are created again, and get on_init once they succeed.
+/// An instance keeps its [`InstanceKind`] until its entities are spawned again
+pub fn reload_lua_script(
+    world: &mut World,
+    state: &mut SystemState<(
+        EventReader<AssetEvent<LuaScript>>,
+        Res<AssetServer>,
+        ResMut<SharedInstances>,
+        Res<Assets<LuaScript>>,
+        Res<LoadedModList>,
+        Res<VirtualFileOverrides>,
+    )>,
+) {
+    let lua_world = unsafe { LuaWorld::new(world) };
+    let (mut events, asset_server, mut instances, lua_scripts, loaded_ml, file_overrides) = state.get_mut(world);
+    let instances = &mut *instances;
+    let modified: HashSet<Handle<LuaScript>> = events.iter()
+        .filter_map(|ev| match ev {
+            AssetEvent::Modified { handle } => Some(handle.clone_weak()),
+            _ => None,
+        })
+        .collect();
+    for handle in modified {
+        let Some(script) = lua_scripts.get(&handle) else {
+            continue;
+        };
+        let ids: Vec<u32> = instances.instances.iter()
+            .filter(|(_, inst)| inst.handle == handle)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            let inst = instances.instances.get_mut(&id).unwrap();
+            match &inst.result {
+                Ok(inst_ref) => {
+                    let lua = inst_ref.lock.write();
+                    let result = reload_script_on_lua(&lua, script, &inst.path).and_then(|_| script_hooks(&lua));
+                    match result {
+                        Ok(hooks)  => instances.subscribers.subscribe(id, hooks),
+                        Err(err)   => report_error(&lua, "reload", None, &err),
+                    }
+                },
+                Err(_) => {
+                    let permissions = loaded_ml.script_permissions(&inst.path, &file_overrides);
+                    inst.result = load_script(script, &inst.path, lua_world.clone(), id, &permissions);
+                    // only the recovered instance is initialized, since the other scripts on its entities already were
+                    if let Ok(inst_ref) = &inst.result {
+                        let hook = Hook { name: ON_INIT.to_string(), args: default() };
+                        for (entity, refs) in instances.by_entity.iter() {
+                            if refs.contains(&id) {
+                                let _ = hook.exec_replying(&inst_ref.lock, (*entity).into(), None).map_err(|e| {
+                                    hook.log_err(&inst_ref.lock.read(), (*entity).into(), e);
+                                });
+                            }
+                        }
+                        match script_hooks(&inst_ref.lock.read()) {
+                            Ok(hooks) => instances.subscribers.subscribe(id, hooks),
+                            Err(e)    => error!("Failed to get the hooks of script {}: {}", id, e),
+                        }
+                    }
+                },
+            }
+        }
+
+        let Some(path) = asset_server.get_handle_path(&handle).and_then(|p| p.path().to_str().map(|s| s.to_string())) else {
+            continue;
+        };
+        let is_collectivist = instances.by_path.get(&path)
+            .map_or(false, |entities| entities.values().any(|id| instances.instance_of(*id) == SharedInstances::COLLECTIVIST_ID));
+        if is_collectivist {
+            let lua = instances.collectivist.lock.write();
+            match reload_script_on_lua(&lua, script, &path).and_then(|_| script_hooks(&lua)) {
+                Ok(hooks) => instances.subscribers.subscribe(SharedInstances::COLLECTIVIST_ID, hooks),
+                Err(err)  => report_error(&lua, "reload", None, &err),
+            }
+        }
+    }
+    state.apply(world);
+}
+
 pub fn update_script_queue(
-    mut si:    ResMut<SharedInstances>,
-    mut query: Query<(Entity, &mut LuaQueue, &ScriptRefs)>,
+    mut si:       ResMut<SharedInstances>,
+    mut messages: ResMut<LuaMessages>,
+    mut query:    Query<(Entity, &mut LuaQueue, &ScriptRefs)>,
 ) {
+    // hooks can send messages themselves, so the calls they answer are only settled once they've all run
+    let mut answered = Vec::new();
     for (entity, mut queue, script_ref) in query.iter_mut() {
         if !queue.calls.is_empty() {
             // info!("{:?} trying queue {:?}", entity, queue.calls);
         }
-        queue.calls.retain(|HookCall { hook, script_ids }| {
+        queue.calls.retain(|HookCall { hook, script_ids, reply_to }| {
             if script_ids.is_empty() || script_ids.iter().all(|i| *i == SharedInstances::COLLECTIVIST_ID || si.instances.contains_key(i)) {
                 // info!("{:?} consuming hook {:?}", entity, hook);
                 for id in script_ref.ids.iter() {
-                    let lua_inst = si.instances.get(id).unwrap();
-                    if let Ok(inst_ref) = &lua_inst.result {
-                        let _ = hook.exec(&inst_ref.lock, entity.into()).map_err(|e| {
-                            hook.log_err(e);
+                    if let Some(inst_ref) = si.get_loaded(*id) {
+                        let _ = hook.exec_replying(&inst_ref.lock, entity.into(), messages.reply_to(*reply_to)).map_err(|e| {
+                            hook.log_err(&inst_ref.lock.read(), entity.into(), e);
                         });
-                        if *id != SharedInstances::COLLECTIVIST_ID && hook.name.as_str() == ON_INIT {
-                            let mut events = EventFlag::empty();
-                            if (|| {
-                                let r = inst_ref.lock.read();
-                                let globals = r.globals();
-                                if globals.contains_key(ON_UPDATE)? {
-                                    events |= EventFlag::ON_UPDATE;
-                                }
-                                if globals.contains_key(ON_ROOM_REVEAL)? {
-                                    events |= EventFlag::ON_ROOM_REVEAL;
-                                }
-                                Ok(())
-                            })().map_err(|e: mlua::Error| {
-                                error!("Failed to get EventFlags for script {}: {}", *id, e);
-                            }).is_ok() {
-                                si.event_flags.insert(*id, events);
+                        if hook.name.as_str() == ON_INIT {
+                            match script_hooks(&inst_ref.lock.read()) {
+                                Ok(hooks) => si.subscribers.subscribe(*id, hooks),
+                                Err(e)    => error!("Failed to get the hooks of script {}: {}", *id, e),
                             }
                         }
                     }
                 }
+                answered.extend(reply_to.map(|r| (entity, r.id)));
+                false
+            } else if let Some(reply_to) = reply_to {
+                // messages only name instances that existed when they were sent, so a missing one was dropped and won't load again
+                answered.push((entity, reply_to.id));
                 false
             } else { true }
         });
     }
+    for (entity, id) in answered {
+        messages.unqueue(entity, id);
+    }
+}
+
+/// Delivers messages sent with send_after once they're due, then calls back the senders of messages with their replies
+pub fn deliver_lua_messages(world: &mut World) {
+    let now = world.resource::<Time>().elapsed_seconds_f64();
+    let (due, replies) = {
+        let mut messages = world.resource_mut::<LuaMessages>();
+        let (due, delayed): (Vec<DelayedMessage>, _) = std::mem::take(&mut messages.delayed).into_iter().partition(|m| m.due <= now);
+        messages.delayed = delayed;
+        (due, std::mem::take(&mut messages.replies))
+    };
+    for DelayedMessage { message, reply_to, .. } in due {
+        let _ = message.deliver(world, Some(reply_to)).map_err(|e| {
+            error!("Failed to deliver message {} sent by script id #{}: {}", message.hook_name, reply_to.script_id, e);
+        });
+        world.resource_mut::<LuaMessages>().settle(reply_to.id);
+    }
+
+    let mut settled = Vec::new();
+    {
+        let si = world.resource::<SharedInstances>();
+        for (reply_to, args) in replies {
+            settled.push(reply_to.id);
+            // replies to an entity that's gone have nowhere to go
+            if reply_to.entity.map_or(false, |e| world.get_entity(e).is_none()) {
+                continue;
+            }
+            if let Some(args) = args && let Some(inst_ref) = si.get_loaded(reply_to.script_id) {
+                let _ = call_reply(&inst_ref.lock.write(), reply_to, args);
+            }
+        }
+    }
+    let finished = {
+        let mut messages = world.resource_mut::<LuaMessages>();
+        for id in settled {
+            messages.settle(id);
+        }
+        messages.take_settled()
+    };
+    let si = world.resource::<SharedInstances>();
+    for reply_to in finished {
+        if let Some(inst_ref) = si.get_loaded(reply_to.script_id) {
+            let _ = forget_reply(&inst_ref.lock.write(), reply_to.id).map_err(|e| {
+                error!("Failed to drop the reply callback of script id #{}: {}", reply_to.script_id, e);
+            });
+        }
+    }
+}
+
+/// Resumes coroutines whose wait is over, at the same fixed timestep as on_update
+pub fn resume_lua_threads(
+    time: Res<Time>,
+    si:   Res<SharedInstances>,
+) {
+    let elapsed = time.elapsed_seconds_f64();
+    for (id, inst_ref) in si.loaded() {
+        let lua = inst_ref.lock.write();
+        let _ = resume_waiting(&lua, |until| until.tick(elapsed), ()).map_err(|e| {
+            error!("Failed to resume coroutines of script id #{:?}: {}", id, e);
+        });
+    }
+}
+
+/// Calls back the timers of every instance once they're due
+pub fn fire_lua_timers(
+    time: Res<Time>,
+    si:   Res<SharedInstances>,
+) {
+    let elapsed = time.elapsed_seconds_f64();
+    for (id, inst_ref) in si.loaded() {
+        let lua = inst_ref.lock.write();
+        let _ = fire_timers(&lua, elapsed).map_err(|e| {
+            error!("Failed to fire timers of script id #{:?}: {}", id, e);
+        });
+    }
 }
 
+/// Calls each queued event, then on_update, on the scripts that define their hooks
 pub fn update_script_event_queue(
     time:                Res<Time>,
     mut lua_event_queue: ResMut<LuaEventQueue>,
     mut lua_time:        ResMut<LuaTime>,
-    si:                  ResMut<SharedInstances>,
-    query:               Query<(Entity, &ScriptRefs), >,
+    si:                  Res<SharedInstances>,
 ) {
     let elapsed = time.elapsed_seconds_f64();
     let delta = if lua_time.elapsed > 0. { elapsed - lua_time.elapsed } else { 0. };
     lua_time.elapsed = elapsed;
     lua_time.delta = delta;
 
-    for EventCall { flag, hook } in lua_event_queue.calls.drain(..) {
-        for (entity, script_ref) in query.iter() {
-            for id in script_ref.ids.iter() {
-                if si.has_event_flags(flag, *id) {
-                    let lua_inst = si.instances.get(id).unwrap();
-                    if let Ok(inst_ref) = &lua_inst.result {
-                        let _ = hook.exec(&inst_ref.lock, entity.into()).map_err(|e| {
-                            hook.log_err(e);
-                        });
-                    }
-                }
+    // events emitted by these hooks are queued for the next update rather than added to this one
+    for EventCall { hook } in std::mem::take(&mut lua_event_queue.calls) {
+        for (id, entity) in si.subscribers.subscribed(&hook.name) {
+            if let Some(inst_ref) = si.get_loaded(id) {
+                let _ = hook.exec(&inst_ref.lock, entity.into()).map_err(|e| {
+                    hook.log_err(&inst_ref.lock.read(), entity.into(), e);
+                });
             }
         }
     }
-    for (entity, script_ref) in query.iter() {
-        for id in script_ref.ids.iter() {
-            if si.has_event_flags(EventFlag::ON_UPDATE, *id) {
-                let lua_inst = si.instances.get(id).unwrap();
-                if let Ok(inst_ref) = &lua_inst.result {
-                    let _ = (|| {
-                        let lua = inst_ref.lock.write();
-                        lua.globals().set("entity", LuaEntity(entity))?;
-                        if let Some(f) = lua.globals().get::<_, Option<LuaFunction>>(ON_UPDATE)? {
-                            f.call(lua_time.clone().to_lua_multi(&lua)?)?;
-                        }
-                        Ok(())
-                    })().map_err(|e: mlua::Error| {
-                        error!("{:?} script id #{:?} on_update error {}", entity, id, e);
-                    });
+    for (id, entity) in si.subscribers.subscribed(ON_UPDATE) {
+        if let Some(inst_ref) = si.get_loaded(id) {
+            let _ = (|| {
+                let lua = inst_ref.lock.write();
+                lua.globals().set("entity", LuaEntity(entity))?;
+                if let Some(f) = lua.globals().get::<_, Option<LuaFunction>>(ON_UPDATE)? {
+                    profiled_call(&lua, &f, ON_UPDATE, Some(entity), || budgeted(&lua, || f.call(lua_time.clone().to_lua_multi(&lua)?)))?;
                 }
-            }
+                Ok(())
+            })().map_err(|e: mlua::Error| {
+                report_error(&inst_ref.lock.read(), ON_UPDATE, Some(entity), &e);
+            });
         }
     }
 }
@@ -395,10 +788,95 @@ pub fn on_asset_load(
             let v: Vec<LuaFunction> = lua.registry_value(&reg_key).unwrap();
             lua.remove_registry_value(reg_key).unwrap();
             for f in v {
-                f.call::<_, ()>(handle.clone()).unwrap();
+                let _ = profiled_call(&lua, &f, "on_load", entity, || budgeted(&lua, || f.call::<_, ()>(handle.clone()))).map_err(|err| {
+                    report_error(&lua, "on_load", entity, &err);
+                });
             }
         } else {
             info!("Lua script {:?} failed to load, so all on_load events for {:?} will be dropped", script_id, entity);
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records that `entity` runs the script at `path` under `id`, in a unique or shared instance if `instance` is set, or else in the collectivist
+    fn attach(si: &mut SharedInstances, entity: Entity, path: &str, id: u32, instance: bool) {
+        if instance && !si.instances.contains_key(&id) {
+            si.instances.insert(id, LuaInstance { handle: default(), path: path.to_string(), result: Err(LuaError::RuntimeError("unloaded".to_string())) });
+        }
+        si.by_path.entry(path.to_string()).or_default().insert(entity, id);
+        si.by_entity.entry(entity).or_default().insert(si.instance_of(id));
+        si.subscribers.subscribe(si.instance_of(id), HashSet::from([ON_UPDATE.to_string()]));
+        si.subscribers.attach(entity, si.instance_of(id));
+    }
+
+    #[test]
+    fn release_unique() {
+        let mut si = SharedInstances::default();
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        attach(&mut si, a, "door.lua", 1, true);
+        attach(&mut si, b, "door.lua", 2, true);
+
+        assert_eq!(HashSet::from([1]), si.release(a, &HashSet::from([1])));
+        assert!(!si.instances.contains_key(&1));
+        assert!(!si.by_entity.contains_key(&a));
+        assert_eq!(HashMap::from([(b, 2)]), si.by_path["door.lua"]);
+        assert!(!si.subscribers.is_subscribed(1, ON_UPDATE));
+        assert_eq!(vec![(2, b)], si.subscribers.subscribed(ON_UPDATE));
+
+        assert_eq!(HashSet::from([2]), si.release(b, &HashSet::from([2])));
+        assert!(si.instances.is_empty() && si.by_path.is_empty() && si.by_entity.is_empty());
+    }
+
+    #[test]
+    fn release_shared() {
+        let mut si = SharedInstances::default();
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        attach(&mut si, a, "torch.lua", 1, true);
+        attach(&mut si, b, "torch.lua", 1, true);
+
+        // b still runs the instance, so it's kept, but a no longer gets its events
+        assert!(si.release(a, &HashSet::from([1])).is_empty());
+        assert!(si.instances.contains_key(&1));
+        assert_eq!(HashMap::from([(b, 1)]), si.by_path["torch.lua"]);
+        assert_eq!(vec![(1, b)], si.subscribers.subscribed(ON_UPDATE));
+
+        assert_eq!(HashSet::from([1]), si.release(b, &HashSet::from([1])));
+        assert!(si.instances.is_empty() && si.by_path.is_empty() && si.subscribers.subscribed(ON_UPDATE).is_empty());
+    }
+
+    #[test]
+    fn release_collectivist() {
+        let mut si = SharedInstances::default();
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        attach(&mut si, a, "weather.lua", 3, false);
+        attach(&mut si, a, "music.lua", 4, false);
+        attach(&mut si, b, "weather.lua", 5, false);
+
+        // collectivist scripts are recorded under their own ids, which are all released along with the collectivist's
+        assert!(si.release(a, &HashSet::from([SharedInstances::COLLECTIVIST_ID])).is_empty());
+        assert!(!si.by_path.contains_key("music.lua"));
+        assert_eq!(HashMap::from([(b, 5)]), si.by_path["weather.lua"]);
+        assert!(si.release(b, &HashSet::from([SharedInstances::COLLECTIVIST_ID])).is_empty());
+        assert!(si.by_path.is_empty() && si.by_entity.is_empty());
+        assert!(si.get_loaded(SharedInstances::COLLECTIVIST_ID).is_some());
+    }
+
+    #[test]
+    fn detach_scripts() {
+        let mut si = SharedInstances::default();
+        let entity = Entity::from_raw(1);
+        attach(&mut si, entity, "door.lua", 1, true);
+        attach(&mut si, entity, "weather.lua", 2, false);
+        attach(&mut si, entity, "music.lua", 3, false);
+
+        assert_eq!(Some(1), si.detach(entity, "door.lua"));
+        assert_eq!(None, si.detach(entity, "door.lua"));
+        // the entity still runs music.lua in the collectivist
+        assert_eq!(None, si.detach(entity, "weather.lua"));
+        assert_eq!(Some(SharedInstances::COLLECTIVIST_ID), si.detach(entity, "music.lua"));
+        assert!(si.by_path.is_empty());
+    }
+}
@@ -1,10 +1,12 @@
-use std::{collections::{HashMap, VecDeque}, fs::File, io::Write, path::PathBuf};
-use bevy::{prelude::{*}, asset::{FileAssetIo, LoadState}};
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt, fs::File, io::Write, path::{Path, PathBuf}};
+use bevy::{prelude::{*}, asset::{FileAssetIo, LoadState, HandleId, AssetPath}};
+use bevy_egui::{egui, EguiContext};
 use indexmap::IndexMap;
+use once_cell::sync::OnceCell;
 use iyes_loopless::prelude::IntoConditionalSystem;
 use ron::ser::PrettyConfig;
 
-use crate::{data::{module::{Module, ModuleLoader, ModList, ModEntry}, lua::LuaScript, assetio::{VirtualFileOverrides}}, system::lua::ToInitScripts, util::ron_options};
+use crate::{data::{module::{Module, ModuleLoader, ModList, ModEntry, ModError, LoadEntry, Version}, lua::{LuaScript, ScriptVar}, assetio::{VirtualFileOverrides, VirtualFile}, fingerprint::ModlistFingerprint}, scripting::permission::ScriptPermissions, system::lua::ToInitScripts, util::ron_options};
 
 use super::lua::{SharedInstances, LuaQueue};
 
@@ -18,99 +20,314 @@ impl Plugin for ModulePlugin {
             .add_asset_loader(ModuleLoader::default())
             .init_resource::<LoadedModList>()
             .init_resource::<ModLoadState>()
+            .init_resource::<ModLoadReport>()
+            .add_event::<ReloadMods>()
             .add_startup_system(setup_modlist)
             .add_system(load_mods.run_if_resource_exists::<ModLoadState>())
+            .add_system(reload_mods)
+            .add_system(show_mod_load_report)
         ;
     }
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Resource)]
 pub struct LoadedModList {
+    /// The modlist file that was read, which depends on the active profile
+    pub file:        String,
     pub modlist:     ModList,
     pub handles:     HashMap<String, Handle<Module>>,
     pub root_dirs:   HashMap<String, String>,
+    pub permissions: HashMap<String, HashSet<String>>,
+    /// Each mod's settings, resolved against its schema from the values in its modlist entry
+    pub settings:    HashMap<String, HashMap<String, ScriptVar>>,
+    /// Every mod that was loaded, in waves that load in order
+    pub load_order:  Vec<Vec<String>>,
+    /// Each loaded mod's name and version, in load order
+    pub versions:    Vec<(String, Version)>,
+    /// What co-op peers compare to check that they run the same mods and files, found by [`Self::fingerprint`]
+    pub fingerprint: OnceCell<ModlistFingerprint>,
+    /// The entities running each mod's startup scripts, torn down when the mods are reloaded
+    pub startup_entities: Vec<Entity>,
+}
+impl LoadedModList {
+    /// What co-op peers compare to check that they run the same mods and files
+    /// 
+    /// It hashes every asset file, so it's only found the first time it's needed after the mods load
+    pub fn fingerprint(&self, file_overrides: &VirtualFileOverrides) -> &ModlistFingerprint {
+        self.fingerprint.get_or_init(|| {
+            ModlistFingerprint::new(self.versions.clone(), &file_overrides.overrides.read(), &FileAssetIo::get_base_path().join("assets"))
+                .unwrap_or_else(|e| {
+                    error!("Unable to fingerprint the modlist: {}", e);
+                    default()
+                })
+        })
+    }
+
+    /// Finds the mod that provides the script at this asset path, and the permissions it was granted
+    /// 
+    /// Anything not overridden by a mod comes from the `assets` directory of the core mod
+    pub fn script_permissions(&self, path: &str, file_overrides: &VirtualFileOverrides) -> ScriptPermissions {
+        let root = file_overrides.owner(Path::new(path)).map(|e| e.root.as_str()).unwrap_or("assets");
+        match self.root_dirs.iter().find(|(_, dir)| dir.as_str() == root) {
+            Some((name, _)) => ScriptPermissions::new(name.clone(), self.permissions.get(name).cloned().unwrap_or_default()),
+            None            => ScriptPermissions::new(root.to_string(), HashSet::new()),
+        }
+    }
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq, Resource)]
+#[derive(Clone, Debug, Eq, PartialEq, Resource)]
 pub enum ModLoadState {
-    #[default]
-    LoadingModDefs,
+    /// Waiting for the previous mod definitions to be freed, so they are read from disk again instead of reused
+    Unloading {
+        mod_defs: Vec<HandleId>,
+    },
+    LoadingModDefs {
+        /// Whether assets that were loaded before the overrides changed should be read again
+        reload_assets: bool,
+    },
     LoadingScripts {
         remaining: VecDeque<Vec<String>>,
         current:   Vec<Entity>,
     },
 }
 
+/// Everything that went wrong while loading the modlist, and which mods were disabled because of it
+#[derive(Clone, Debug, Default, PartialEq, Resource)]
+pub struct ModLoadReport {
+    pub errors:    Vec<ModLoadError>,
+    pub disabled:  HashSet<String>,
+    pub dismissed: bool,
+}
+impl ModLoadReport {
+    pub fn disable(&mut self, name: &String, error: ModLoadError) {
+        error!("{}", error);
+        self.disabled.insert(name.clone());
+        self.errors.push(error);
+    }
+
+    /// Describes what the errors mean for this session, since only some kinds of error disable mods
+    pub fn summary(&self, file: &str) -> String {
+        let mut summary = format!("{} problem(s) were found while loading {}.", self.errors.len(), file);
+        if self.errors.iter().any(|e| matches!(e, ModLoadError::ModList(_))) {
+            summary.push_str(" Only the core mod will be loaded.");
+        }
+        if !self.disabled.is_empty() {
+            summary.push_str(" The game will continue without the mods below.");
+        } else if self.errors.iter().any(|e| matches!(e, ModLoadError::InvalidSetting { .. })) {
+            summary.push_str(" Every mod is still loaded, but the settings below were not applied as written.");
+        }
+        summary
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModLoadError {
+    ModList(String),
+    MissingDirectory {
+        name: String,
+        path: String,
+    },
+    FailedToLoad {
+        name: String,
+        file: String,
+    },
+    /// The mod's directory or archive couldn't be read, like when a zip is corrupt
+    Unreadable {
+        name:    String,
+        path:    String,
+        message: String,
+    },
+    InvalidSetting {
+        name:    String,
+        message: String,
+    },
+    Invalid(ModError),
+}
+impl fmt::Display for ModLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModLoadError::ModList(e)                     => write!(f, "{}", e),
+            ModLoadError::MissingDirectory { name, path } => write!(f, "Mod `{}` has no directory at {}", name, path),
+            ModLoadError::FailedToLoad { name, file }     => write!(f, "Mod `{}` failed to load {}", name, file),
+            ModLoadError::Unreadable { name, path, message } => write!(f, "Mod `{}` couldn't be read from {}: {}", name, path, message),
+            ModLoadError::InvalidSetting { name, message } => write!(f, "Mod `{}`: {}", name, message),
+            ModLoadError::Invalid(e)                     => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Default for ModLoadState {
+    fn default() -> Self { ModLoadState::LoadingModDefs { reload_assets: false } }
+}
+
+/// Sent to tear down every mod and load the modlist again, without restarting
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReloadMods;
+
+/// Reloads the mods in debug builds
+pub const RELOAD_MODS_KEY: KeyCode = KeyCode::F5;
+
 pub const THIS_MODLIST_FILE: &str = "this.modlist.ron";
+/// Named modlist profiles live here as `<name>.modlist.ron`, each with its own entries and settings
+pub const PROFILE_DIR: &str = "profiles";
+pub const PROFILE_ARG: &str = "--profile";
+pub const PROFILE_ENV: &str = "BIFRONS_MODLIST_PROFILE";
+
+/// The profile picked by `--profile <name>` on the command line, or else by the `BIFRONS_MODLIST_PROFILE` environment variable
+pub fn active_profile() -> Option<String> {
+    let mut args = std::env::args().skip_while(|a| a != PROFILE_ARG).skip(1);
+    args.next()
+        .or_else(|| std::env::var(PROFILE_ENV).ok())
+        .filter(|p| !p.is_empty())
+}
+
+/// The modlist file for a profile, relative to the game's root, which is `this.modlist.ron` without one
+pub fn modlist_file(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{}/{}.modlist.ron", PROFILE_DIR, profile),
+        None          => THIS_MODLIST_FILE.to_string(),
+    }
+}
+
+pub fn default_modlist() -> ModList {
+    ModList {
+        entries: vec![ModEntry {
+            file:     "assets/core.mod.ron".to_string(),
+            settings: HashMap::new(),
+            enabled:  true,
+            name:     "core".to_string(),
+        }],
+    }
+}
 
 pub fn setup_modlist(
-    asset_server:     Res<AssetServer>,
-    mut loaded_ml:    ResMut<LoadedModList>,
+    asset_server:       Res<AssetServer>,
+    file_overrides:     Res<VirtualFileOverrides>,
+    mut loaded_ml:      ResMut<LoadedModList>,
+    mut report:         ResMut<ModLoadReport>,
+) {
+    read_modlist(&file_overrides, &mut loaded_ml, &mut report);
+    load_mod_defs(&asset_server, &mut loaded_ml, &report);
+}
+
+/// Reads the active profile's modlist and finds each mod's root directory
+/// 
+/// Without a profile, `this.modlist.ron` is read, or written with only the core mod if there is none.
+/// A profile that doesn't exist falls back to only the core mod
+pub fn read_modlist(
+    file_overrides: &VirtualFileOverrides,
+    loaded_ml:      &mut LoadedModList,
+    report:         &mut ModLoadReport,
 ) {
+    let profile = active_profile();
+    let file = modlist_file(profile.as_deref());
     let mut modlist_path = FileAssetIo::get_base_path();
-    modlist_path.push(THIS_MODLIST_FILE);
+    modlist_path.push(&file);
+    loaded_ml.file = file.clone();
 
     if modlist_path.is_file() {
-        let bytes = match std::fs::read(modlist_path) {
-            Ok(file) => file,
-            Err(e)   => panic!("Unable to open {}: {}", THIS_MODLIST_FILE, e),
-        };
-
-        loaded_ml.modlist = match ron_options().from_bytes(&bytes) {
-            Ok(file) => file,
-            Err(e)   => panic!("Unable to deserialize {}: {}", THIS_MODLIST_FILE, e),
+        if let Some(profile) = &profile {
+            info!("Using modlist profile `{}`", profile);
+        }
+        let modlist = std::fs::read(modlist_path)
+            .map_err(|e| format!("Unable to open {}: {}", file, e))
+            .and_then(|bytes| ron_options().from_bytes(&bytes)
+                .map_err(|e| format!("Unable to deserialize {}: {}", file, e)));
+        loaded_ml.modlist = match modlist {
+            Ok(modlist) => modlist,
+            Err(e)      => {
+                error!("{}; only the core mod will be loaded", e);
+                report.errors.push(ModLoadError::ModList(e));
+                default_modlist()
+            },
         };
+    } else if let Some(profile) = &profile {
+        let e = format!("There is no modlist profile `{}` at {}", profile, file);
+        error!("{}; only the core mod will be loaded", e);
+        report.errors.push(ModLoadError::ModList(e));
+        loaded_ml.modlist = default_modlist();
     } else {
-        loaded_ml.modlist.entries = vec![ModEntry {
-            file:     "assets/core.mod.ron".to_string(),
-            settings: HashMap::new(),
-            name:     "core".to_string(),
-        }];
+        loaded_ml.modlist = default_modlist();
 
         let s = ron::ser::to_string_pretty(&loaded_ml.modlist, PrettyConfig::default())
             .expect("ModList should never fail to serialize");
-        let mut file = match File::create(modlist_path) {
-            Ok(file) => file,
-            Err(e)   => panic!("Unable to create {}: {}", THIS_MODLIST_FILE, e),
-        };
-        if let Err(e) = file.write(s.as_bytes()) {
-            panic!("Unable to write to {}: {}", THIS_MODLIST_FILE, e)
+        let written = File::create(modlist_path)
+            .map_err(|e| format!("Unable to create {}: {}", THIS_MODLIST_FILE, e))
+            .and_then(|mut file| file.write(s.as_bytes())
+                .map_err(|e| format!("Unable to write to {}: {}", THIS_MODLIST_FILE, e)));
+        if let Err(e) = written {
+            error!("{}", e);
+            report.errors.push(ModLoadError::ModList(e));
         }
     }
-    let handles = loaded_ml.modlist.entries.iter()
-        .map(|me| (me.name.clone(), asset_server.load(&format!("../{}", me.file))))
-        .collect();
-    loaded_ml.handles = handles;
 
-    loaded_ml.root_dirs = loaded_ml.modlist.entries.iter()
-        .map(|e| (e.name.clone(), {
-            let mut path = PathBuf::from(&e.file);
-            path.pop();
-            if !path.is_dir() {
-                panic!("Failed to get directory of mod {}: found {}", e.name, path.to_string_lossy())
+    let mut root_dirs = HashMap::new();
+    for entry in loaded_ml.modlist.entries.iter().filter(|e| e.enabled) {
+        if entry.is_zip() {
+            // the archive stands in for the mod's directory, and its .mod.ron is served straight out of it
+            let archive = FileAssetIo::get_base_path().join(&entry.file);
+            if archive.is_file() {
+                root_dirs.insert(entry.name.clone(), entry.file.clone());
+                file_overrides.overrides.write().insert(PathBuf::from(entry.mod_def_path()), VirtualFile::Zip {
+                    archive,
+                    entry: format!("{}.mod.ron", entry.name),
+                });
+            } else {
+                report.disable(&entry.name, ModLoadError::MissingDirectory { name: entry.name.clone(), path: entry.file.clone() });
             }
-            path.to_string_lossy().to_string()
-        }))
+            continue;
+        }
+        let mut path = PathBuf::from(&entry.file);
+        path.pop();
+        if path.is_dir() {
+            root_dirs.insert(entry.name.clone(), path.to_string_lossy().to_string());
+        } else {
+            report.disable(&entry.name, ModLoadError::MissingDirectory { name: entry.name.clone(), path: path.to_string_lossy().to_string() });
+        }
+    }
+    loaded_ml.root_dirs = root_dirs;
+}
+
+pub fn load_mod_defs(asset_server: &AssetServer, loaded_ml: &mut LoadedModList, report: &ModLoadReport) {
+    let handles = loaded_ml.modlist.entries.iter()
+        .filter(|me| me.enabled && !report.disabled.contains(&me.name))
+        .map(|me| (me.name.clone(), asset_server.load(&me.mod_def_path())))
         .collect();
+    loaded_ml.handles = handles;
 }
 
 pub fn load_mods(
     mut commands:       Commands,
     mods:               Res<Assets<Module>>,
-    loaded_ml:          Res<LoadedModList>,
+    mut loaded_ml:      ResMut<LoadedModList>,
     asset_server:       Res<AssetServer>,
     mut file_overrides: ResMut<VirtualFileOverrides>,
     mut lua_instances:  ResMut<SharedInstances>,
     mut mls:            ResMut<ModLoadState>,
+    mut report:         ResMut<ModLoadReport>,
     query:              Query<Entity, With<LuaQueue>>,
 ) {
     match mls.as_mut() {
-        ModLoadState::LoadingModDefs => {
+        ModLoadState::Unloading { mod_defs } => {
+            // freed assets report Unloaded, while ones that never finished loading may not have a state at all
+            if mod_defs.iter().all(|id| matches!(asset_server.get_load_state(*id), LoadState::Unloaded | LoadState::NotLoaded | LoadState::Failed)) {
+                load_mod_defs(&asset_server, &mut loaded_ml, &report);
+                *mls = ModLoadState::LoadingModDefs { reload_assets: true };
+            }
+        },
+        ModLoadState::LoadingModDefs { reload_assets } => {
             let mut is_done = true;
             for (name, handle) in loaded_ml.handles.iter() {
+                if report.disabled.contains(name) {
+                    continue;
+                }
                 match asset_server.get_load_state(handle) {
                     LoadState::Failed => {
-                        panic!("{}.mod.ron failed to load", name);
+                        let file = loaded_ml.modlist.entries.iter()
+                            .find(|e| &e.name == name)
+                            .map(|e| e.file.clone())
+                            .unwrap_or_else(|| format!("{}.mod.ron", name));
+                        report.disable(name, ModLoadError::FailedToLoad { name: name.clone(), file });
                     },
                     LoadState::Loaded => (),
                     _ => { is_done = false; },
@@ -118,20 +335,70 @@ pub fn load_mods(
             }
 
             if is_done {
-                let mod_map = loaded_ml.handles.iter()
+                let mut mod_map: HashMap<String, &Module> = loaded_ml.handles.iter()
+                    .filter(|(k, _)| !report.disabled.contains(*k))
                     .map(|(k, v)| (k.clone(), mods.get(&v).unwrap()))
                     .collect();
-        
-                let ordered = match Module::sorted_load_order(&mod_map) {
-                    Ok(v) => v,
-                    Err(e) => { panic!("Modlist validation failed: {:?}", e) },
+
+                let previous = file_overrides.overrides.read().clone();
+                // a mod whose files can't be read is disabled, and the load order found again without it and its dependents
+                let (ordered, errors) = loop {
+                    let (ordered, errors) = Module::recovered_load_order(&mod_map);
+                    let mod_path_load_order = ordered.iter()
+                        .map(|v| v.iter().map(|s| LoadEntry { name: s.clone(), root: loaded_ml.root_dirs[s].clone() }).collect())
+                        .collect();
+                    let unreadable = file_overrides.populate_files(&mod_path_load_order);
+                    if unreadable.is_empty() {
+                        break (ordered, errors);
+                    }
+                    for (entry, e) in unreadable {
+                        report.disable(&entry.name, ModLoadError::Unreadable { name: entry.name.clone(), path: entry.root.clone(), message: e.to_string() });
+                        mod_map.remove(&entry.name);
+                    }
                 };
-                let mod_path_load_order = ordered.iter()
-                    .map(|v| v.iter().map(|s| &loaded_ml.root_dirs[*s]).collect())
+                for e in errors {
+                    report.disable(&e.at_fault().clone(), ModLoadError::Invalid(e));
+                }
+                let permissions = ordered.iter().flatten()
+                    .map(|name| (name.clone(), mod_map[name].permissions.clone()))
                     .collect();
-                file_overrides.populate_files(&mod_path_load_order);
+                let mut settings = HashMap::new();
+                for name in ordered.iter().flatten() {
+                    let values = loaded_ml.modlist.entries.iter()
+                        .find(|e| &e.name == name)
+                        .map(|e| e.settings.clone())
+                        .unwrap_or_default();
+                    let (resolved, errors) = mod_map[name].resolve_settings(&values);
+                    for message in errors {
+                        warn!("Mod `{}`: {}", name, message);
+                        report.errors.push(ModLoadError::InvalidSetting { name: name.clone(), message });
+                    }
+                    settings.insert(name.clone(), resolved);
+                }
+                let conflicts = file_overrides.conflict_report();
+                if !conflicts.is_empty() {
+                    info!("Files provided by more than one mod:\n{}", conflicts);
+                }
+                if *reload_assets {
+                    // anything already loaded may now come from a different mod, or its file may have been edited
+                    let overrides = file_overrides.overrides.read();
+                    for path in previous.keys().chain(overrides.keys().filter(|k| !previous.contains_key(*k))) {
+                        let asset_path = AssetPath::from(path.as_path());
+                        if !matches!(asset_server.get_load_state(asset_path.get_id()), LoadState::NotLoaded | LoadState::Unloaded) {
+                            asset_server.reload_asset(asset_path);
+                        }
+                    }
+                }
+
+                loaded_ml.permissions = permissions;
+                loaded_ml.settings    = settings;
+                loaded_ml.load_order  = ordered.clone();
+                loaded_ml.versions    = ordered.iter().flatten()
+                    .map(|name| (name.clone(), mod_map[name].version))
+                    .collect();
+                loaded_ml.fingerprint = OnceCell::new();
 
-                let remaining = ordered.into_iter().map(|v| v.into_iter().cloned().collect()).collect();
+                let remaining = ordered.into_iter().collect();
                 *mls = ModLoadState::LoadingScripts { remaining, current: Vec::new() };
             }
         },
@@ -153,6 +420,7 @@ pub fn load_mods(
                                 ToInitScripts { handles },
                             )).id()
                         }).collect();
+                        loaded_ml.startup_entities.extend(current.iter().copied());
                     } else {
                         commands.remove_resource::<ModLoadState>();
                     }
@@ -160,4 +428,134 @@ pub fn load_mods(
             }
         },
     } 
-}
\ No newline at end of file
+}
+
+/// Tears down every startup script and loads the modlist again, on a [`ReloadMods`] event or [`RELOAD_MODS_KEY`] in debug builds
+/// 
+/// Entities spawned by the startup scripts are left alone, so scripts that spawn things should clean up after themselves in on_drop
+pub fn reload_mods(
+    mut commands:       Commands,
+    mut events:         EventReader<ReloadMods>,
+    keys:               Res<Input<KeyCode>>,
+    mls:                Option<Res<ModLoadState>>,
+    file_overrides:     Res<VirtualFileOverrides>,
+    mut loaded_ml:      ResMut<LoadedModList>,
+    mut report:         ResMut<ModLoadReport>,
+) {
+    let requested = events.iter().count() > 0 || (cfg!(debug_assertions) && keys.just_pressed(RELOAD_MODS_KEY));
+    if !requested {
+        return;
+    }
+    if mls.is_some() {
+        warn!("Mods are still loading, so they can't be reloaded yet");
+        return;
+    }
+    info!("Reloading mods from {}", loaded_ml.file);
+
+    // their scripts get on_drop and are released once the despawn goes through
+    for entity in loaded_ml.startup_entities.drain(..) {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let mod_defs = loaded_ml.handles.drain().map(|(_, handle)| handle.id()).collect();
+    *report = ModLoadReport::default();
+    read_modlist(&file_overrides, &mut loaded_ml, &mut report);
+    commands.insert_resource(ModLoadState::Unloading { mod_defs });
+}
+
+pub fn show_mod_load_report(
+    mut egui_ctx: ResMut<EguiContext>,
+    loaded_ml:    Res<LoadedModList>,
+    mut report:   ResMut<ModLoadReport>,
+) {
+    if report.errors.is_empty() || report.dismissed {
+        return;
+    }
+    let mut dismissed = false;
+    egui::Window::new("Mod loading errors")
+        .collapsible(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(egui_ctx.ctx_mut(), |ui| {
+            ui.label(report.summary(&loaded_ml.file));
+            ui.separator();
+            egui::ScrollArea::vertical().max_height(300.).show(ui, |ui| {
+                for error in report.errors.iter() {
+                    ui.label(error.to_string());
+                }
+            });
+            if !report.disabled.is_empty() {
+                ui.separator();
+                let mut disabled: Vec<&String> = report.disabled.iter().collect();
+                disabled.sort();
+                ui.label(format!("Disabled: {}", disabled.into_iter().map(|s| s.as_str()).intersperse(", ").collect::<String>()));
+            }
+            ui.separator();
+            if ui.button("Continue").clicked() {
+                dismissed = true;
+            }
+        });
+    if dismissed {
+        report.dismissed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::AssetPlugin;
+
+    use crate::data::assetio::VirtualAssetIo;
+
+    use super::*;
+
+    /// Updates until `done` holds, giving the asset server's tasks time to finish loading
+    fn update_until(app: &mut App, done: impl Fn(Option<&ModLoadState>) -> bool) {
+        for _ in 0..500 {
+            if done(app.world.get_resource::<ModLoadState>()) {
+                return;
+            }
+            app.update();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        panic!("Mods got stuck in {:?}", app.world.get_resource::<ModLoadState>());
+    }
+
+    #[test]
+    fn reload_after_unloading() {
+        let asset_io = VirtualAssetIo::new();
+        let overrides = asset_io.overrides();
+        let zipped_dirs = asset_io.zipped_dirs();
+        let mut app = App::new();
+        app
+            .insert_resource(AssetServer::new(asset_io))
+            .insert_resource(VirtualFileOverrides { overrides, zipped_dirs, lua_path: String::new(), providers: default() })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Module>()
+            .add_asset_loader(ModuleLoader::default())
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<SharedInstances>()
+            .init_resource::<LoadedModList>()
+            .init_resource::<ModLoadState>()
+            .init_resource::<ModLoadReport>()
+            .add_event::<ReloadMods>()
+            .add_startup_system(setup_modlist)
+            .add_system(load_mods.run_if_resource_exists::<ModLoadState>())
+            .add_system(reload_mods);
+        update_until(&mut app, |mls| mls.is_none());
+
+        app.world.send_event(ReloadMods);
+        app.update();
+        assert!(matches!(app.world.get_resource::<ModLoadState>(), Some(ModLoadState::Unloading { .. })));
+        // the old mod definitions are freed, so the new ones load from disk again
+        update_until(&mut app, |mls| !matches!(mls, Some(ModLoadState::Unloading { .. })));
+        assert_eq!(Some(&ModLoadState::LoadingModDefs { reload_assets: true }), app.world.get_resource::<ModLoadState>());
+        update_until(&mut app, |mls| mls.is_none());
+
+        // and the next reload isn't refused
+        app.world.send_event(ReloadMods);
+        app.update();
+        assert!(app.world.contains_resource::<ModLoadState>());
+        update_until(&mut app, |mls| mls.is_none());
+    }
+}
+
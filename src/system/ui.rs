@@ -4,7 +4,7 @@ use bevy::prelude::*;
 use bevy_egui::{egui, EguiContext};
 use mlua::prelude::*;
 
-use crate::{scripting::{ui::{elem::*, atom::{LuaAtomRegistry, OrAtom}, font::UIFont}}, data::{lua::InstanceRef, palette::{Palette, ColorCache, LoadedPalettes, DynColor}}};
+use crate::{scripting::{diagnostics::{profiled_call, report_error}, sandbox::budgeted, ui::{elem::*, atom::{LuaAtomRegistry, OrAtom}, font::UIFont}}, data::{lua::InstanceRef, palette::{Palette, ColorCache, LoadedPalettes, DynColor}}};
 
 use super::lua::SharedInstances;
 
@@ -141,8 +141,8 @@ pub fn run_containers(
         if let Some(on_click) = maybe_key {
             let lua = inst_ref.lock.write();
             let f = lua.registry_value::<LuaFunction>(on_click).unwrap();
-            if let Err(e) = f.call::<_, ()>(()) {
-                warn!("ImageButton on_click error: {}", e);
+            if let Err(e) = profiled_call(&lua, &f, "on_click", None, || budgeted(&lua, || f.call::<_, ()>(()))) {
+                report_error(&lua, "on_click", None, &e);
             }
         }
     }